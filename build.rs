@@ -1,4 +1,9 @@
 fn main() {
+    // Redis Module API is necessary only for building the module
+    if std::env::var_os("CARGO_FEATURE_REDIS_MODULE").is_none() {
+        return;
+    }
+
     cc::Build::new()
         .file("src/redismodule.c")
         .include("include/")
@@ -1,4 +1,4 @@
-use crate::hyperminhash::{RegisterVector, NUM_REGISTERS};
+use super::{Params, RegisterVector};
 use super::dma::CByteArray;
 use std::mem::size_of;
 
@@ -10,7 +10,10 @@ pub struct DenseVector {
 
 impl DenseVector {
     pub const SINGLE_REGISTER_BYTES: usize = size_of::<u16>();
-    pub const DENSE_BYTES: usize = NUM_REGISTERS * DenseVector::SINGLE_REGISTER_BYTES;
+
+    pub fn dense_bytes(params: &Params) -> usize {
+        params.num_registers() * DenseVector::SINGLE_REGISTER_BYTES
+    }
 
     pub fn wrap(data: CByteArray) -> Self {
         Self { data, }
@@ -21,6 +21,12 @@ impl CByteArray {
     pub fn len(&self) -> usize {
         self.len
     }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self.underlying, self.len)
+        }
+    }
 }
 
 impl Index<usize> for CByteArray {
@@ -43,7 +49,7 @@ impl IndexMut<usize> for CByteArray {
 
 #[cfg(test)]
 mod tests {
-    use crate::redis::dma::CByteArray;
+    use super::CByteArray;
 
     #[test]
     fn test_new() {
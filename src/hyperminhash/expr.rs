@@ -0,0 +1,342 @@
+//! Set-algebra expressions over multiple sketches.
+//!
+//! Grammar: (`&` binds tighter than `|` and `-`, and all operators are left associative)
+//!
+//! ```text
+//! expr    := term (('|' | '-') term)*
+//! term    := primary ('&' primary)*
+//! primary := operand | '(' expr ')'
+//! ```
+//!
+//! `|`, `&`, `-` denote union, intersection and difference respectively.
+//! Operand is any sequence of characters other than whitespaces and `(`, `)`, `|`, `&`.
+//! Hence `-` is regarded as an operator only if it is separated from operands. (e.g. `a - b`, whereas `a-b` is an operand)
+
+use super::*;
+use super::hash::SketchHasher;
+use super::sketch::{check_minhash, ConfidenceInterval, HyperMinHash};
+
+/// Parsed set-algebra expression. Operands are referred by index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Operand(usize),
+    Union(Box<Expr>, Box<Expr>),
+    Intersection(Box<Expr>, Box<Expr>),
+    Difference(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Expression is not well-formed.
+    Syntax,
+    /// Operand couldn't be resolved.
+    UnknownOperand(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    LParen,
+    RParen,
+    Union,
+    Intersection,
+    Difference,
+    Operand(&'a [u8]),
+}
+
+fn tokenize(expr: &[u8]) -> Vec<Token<'_>> {
+    let is_delimiter = |b: u8| b.is_ascii_whitespace() || b"()|&".contains(&b);
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < expr.len() {
+        match expr[i] {
+            b if b.is_ascii_whitespace() => {},
+            b'(' => tokens.push(Token::LParen),
+            b')' => tokens.push(Token::RParen),
+            b'|' => tokens.push(Token::Union),
+            b'&' => tokens.push(Token::Intersection),
+            _ => {
+                let start = i;
+                while i + 1 < expr.len() && !is_delimiter(expr[i + 1]) {
+                    i += 1;
+                }
+                match &expr[start..=i] {
+                    b"-" => tokens.push(Token::Difference),
+                    operand => tokens.push(Token::Operand(operand)),
+                }
+            },
+        }
+        i += 1;
+    }
+
+    tokens
+}
+
+struct Parser<'a, F> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    resolve: F,
+}
+
+impl <'a, F : Fn(&[u8]) -> Option<usize>> Parser<'a, F> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).cloned()
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.term()?;
+        loop {
+            match self.peek() {
+                Some(Token::Union) => {
+                    self.pos += 1;
+                    lhs = Expr::Union(Box::new(lhs), Box::new(self.term()?));
+                },
+                Some(Token::Difference) => {
+                    self.pos += 1;
+                    lhs = Expr::Difference(Box::new(lhs), Box::new(self.term()?));
+                },
+                _ => return Ok(lhs),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.primary()?;
+        while let Some(Token::Intersection) = self.peek() {
+            self.pos += 1;
+            lhs = Expr::Intersection(Box::new(lhs), Box::new(self.primary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let token = self.peek().ok_or(ParseError::Syntax)?;
+        self.pos += 1;
+
+        match token {
+            Token::Operand(name) =>
+                (self.resolve)(name)
+                    .map(Expr::Operand)
+                    .ok_or_else(|| ParseError::UnknownOperand(name.to_vec())),
+            Token::LParen => {
+                let expr = self.expr()?;
+                match self.peek() {
+                    Some(Token::RParen) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    },
+                    _ => Err(ParseError::Syntax),
+                }
+            },
+            _ => Err(ParseError::Syntax),
+        }
+    }
+}
+
+/// Estimated cardinality of an expression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExprEstimate {
+    union: f64,
+    matched: u64,
+    total: u64,
+    params: Params,
+}
+
+impl ExprEstimate {
+    pub fn cardinality(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.union * self.matched as f64 / self.total as f64
+    }
+
+    /// Confidence interval of the cardinality at given confidence level. (e.g. 0.95)
+    pub fn interval(&self, confidence: f64) -> ConfidenceInterval {
+        if self.total == 0 {
+            return ConfidenceInterval::exact(0.0);
+        }
+        let fraction = self.matched as f64 / self.total as f64;
+        ConfidenceInterval::fraction_of_union(fraction, self.total, self.union, &self.params, confidence)
+    }
+}
+
+impl Expr {
+    /// Parse the expression. Operand names are resolved into indices by `resolve`.
+    pub fn parse<F : Fn(&[u8]) -> Option<usize>>(expr: &[u8], resolve: F) -> Result<Expr, ParseError> {
+        let mut parser = Parser { tokens: tokenize(expr), pos: 0, resolve, };
+        let result = parser.expr()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(ParseError::Syntax);
+        }
+        Ok(result)
+    }
+
+    /// Whether an element belongs to the expression, given membership of the element in each operand.
+    pub fn contains(&self, members: &[bool]) -> bool {
+        match self {
+            Expr::Operand(idx) => members[*idx],
+            Expr::Union(lhs, rhs) => lhs.contains(members) || rhs.contains(members),
+            Expr::Intersection(lhs, rhs) => lhs.contains(members) && rhs.contains(members),
+            Expr::Difference(lhs, rhs) => lhs.contains(members) && !rhs.contains(members),
+        }
+    }
+
+    /// Indices of operands appearing in the expression.
+    pub fn operands(&self) -> Vec<usize> {
+        let mut result = Vec::new();
+        self.collect_operands(&mut result);
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+
+    fn collect_operands(&self, result: &mut Vec<usize>) {
+        match self {
+            Expr::Operand(idx) => result.push(*idx),
+            Expr::Union(lhs, rhs) | Expr::Intersection(lhs, rhs) | Expr::Difference(lhs, rhs) => {
+                lhs.collect_operands(result);
+                rhs.collect_operands(result);
+            },
+        }
+    }
+
+    /// Estimate the cardinality of the expression. `None` operand is regarded as an empty set.
+    ///
+    /// The union of all operands is built by merging sketches, then the fraction of the union
+    /// belonging to the expression is estimated by MinHash. i.e. a non-empty union register is
+    /// regarded as a sample which belongs to each operand whose register equals to the union's.
+    /// Chance collisions of MinHash-bits are not corrected, which is negligible unless R is small.
+    pub fn estimate<T, H>(&self, operands: &[Option<&HyperMinHash<T, H>>]) -> Result<ExprEstimate, Error>
+        where T : RegisterVector, H : SketchHasher + Clone {
+
+        let sketches: Vec<(usize, &HyperMinHash<T, H>)> = self.operands().into_iter()
+            .filter_map(|idx| operands[idx].map(|sketch| (idx, sketch)))
+            .collect();
+
+        let (params, hasher) = match sketches.first() {
+            None => return Ok(ExprEstimate { union: 0.0, matched: 0, total: 0, params: Params::default(), }),
+            Some((_, sketch)) => (sketch.params(), sketch.hasher().clone()),
+        };
+
+        check_minhash(&hasher)?;
+        let mut union = HyperMinHash::with_hasher(new_array_registers(&params), params, hasher);
+        for (_, sketch) in sketches.iter() {
+            union.merge(*sketch)?;
+        }
+
+        let mut members = vec![false; operands.len()];
+        let mut matched = 0u64;
+        let mut total = 0u64;
+        for i in 0..params.num_registers() {
+            let reg = union.registers[i];
+            if reg == 0 {
+                continue;
+            }
+
+            for (idx, sketch) in sketches.iter() {
+                members[*idx] = sketch.registers.register_at(i) == reg;
+            }
+            if self.contains(&members) {
+                matched += 1;
+            }
+            total += 1;
+        }
+
+        Ok(ExprEstimate { union: union.cardinality(), matched, total, params, })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::hash::HashAlgorithm;
+
+    fn resolve(name: &[u8]) -> Option<usize> {
+        match name {
+            b"a" => Some(0),
+            b"b" => Some(1),
+            b"c" => Some(2),
+            b"a-b" => Some(3),
+            _ => None,
+        }
+    }
+
+    fn op(idx: usize) -> Box<Expr> {
+        Box::new(Expr::Operand(idx))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Expr::parse(b"a", resolve), Ok(Expr::Operand(0)));
+        assert_eq!(
+            Expr::parse(b"a | b & c", resolve),
+            Ok(Expr::Union(op(0), Box::new(Expr::Intersection(op(1), op(2))))));
+        assert_eq!(
+            Expr::parse(b"(a|b)&c", resolve),
+            Ok(Expr::Intersection(Box::new(Expr::Union(op(0), op(1))), op(2))));
+        assert_eq!(
+            Expr::parse(b"a - b - c", resolve),
+            Ok(Expr::Difference(Box::new(Expr::Difference(op(0), op(1))), op(2))));
+        assert_eq!(
+            Expr::parse(b" a-b - (b) ", resolve),
+            Ok(Expr::Difference(op(3), op(1))));
+
+        assert_eq!(Expr::parse(b"", resolve), Err(ParseError::Syntax));
+        assert_eq!(Expr::parse(b"a |", resolve), Err(ParseError::Syntax));
+        assert_eq!(Expr::parse(b"(a | b", resolve), Err(ParseError::Syntax));
+        assert_eq!(Expr::parse(b"a b", resolve), Err(ParseError::Syntax));
+        assert_eq!(Expr::parse(b"a | d", resolve), Err(ParseError::UnknownOperand(b"d".to_vec())));
+    }
+
+    #[test]
+    fn test_estimate() {
+        // a = [0, 30000), b = [10000, 40000), c = [20000, 50000)
+        let mut sketches = vec![];
+        for offset in [0, 10000, 20000].iter() {
+            let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+            for i in *offset..(offset + 30000) {
+                sketch.add(format!("id{}", i).as_bytes());
+            }
+            sketches.push(sketch);
+        }
+        let operands: Vec<_> = sketches.iter().map(Some).collect();
+
+        let estimate = |expr: &str| Expr::parse(expr.as_bytes(), resolve).unwrap().estimate(&operands).unwrap();
+        let close = |expr: &str, expected: f64| {
+            let result = estimate(expr).cardinality();
+            assert!((result - expected).abs() < expected * 0.1, "{}: {}", expr, result);
+        };
+
+        close("a | b | c", 50000.0);
+        close("a & b", 20000.0);
+        close("(a | b) & c", 20000.0);
+        close("a - (b | c)", 10000.0);
+        close("a & b & c", 10000.0);
+        close("(a - b) | (c - b)", 20000.0);
+
+        let interval = estimate("a & b").interval(0.95);
+        assert!(interval.lower < 20000.0 && 20000.0 < interval.upper);
+
+        // missing operand is an empty set
+        let partial = [Some(&sketches[0]), None, None];
+        let result = Expr::parse(b"a - b", resolve).unwrap().estimate(&partial).unwrap();
+        assert_eq!(result.cardinality(), sketches[0].cardinality());
+        let result = Expr::parse(b"b & c", resolve).unwrap().estimate(&partial).unwrap();
+        assert_eq!(result.cardinality(), 0.0);
+        assert_eq!(result.interval(0.95), ConfidenceInterval::exact(0.0));
+    }
+
+    #[test]
+    fn test_estimate_incompatible() {
+        let params = Params::default();
+        let small = Params::new(10, 6, 10).unwrap();
+        let murmur3 = HyperMinHash::with_hasher(new_array_registers(&params), params, HashAlgorithm::Murmur3);
+        let xxh3 = HyperMinHash::with_hasher(new_array_registers(&params), params, HashAlgorithm::Xxh3);
+        let other = HyperMinHash::with_hasher(new_array_registers(&small), small, HashAlgorithm::Murmur3);
+
+        let expr = Expr::parse(b"a & b", resolve).unwrap();
+        assert!(expr.estimate(&[Some(&murmur3), Some(&murmur3)]).is_ok());
+        assert_eq!(expr.estimate(&[Some(&murmur3), Some(&xxh3)]), Err(Error::HashMismatch));
+        assert_eq!(expr.estimate(&[Some(&murmur3), Some(&other)]), Err(Error::ParamsMismatch));
+    }
+}
@@ -1,7 +1,160 @@
+//! Hash functions to derive HyperMinHash register values from elements.
+
 use std::ops::BitXor;
+use xxhash_rust::xxh3::xxh3_128_with_seed;
+
+pub const HASH_SEED: u64 = 0x1fb03e03;
+
+/// Provides abstraction of 128-bit hash function used by sketches.
+/// Sketches can be merged or compared only if they are built with the same hash function.
+pub trait SketchHasher {
+    /// Identifier of the hash function recorded in serialized sketches.
+    fn id(&self) -> u8;
+
+    fn hash(&self, element: &[u8]) -> u128;
+
+    /// Whether hashes provide MinHash-part. Sketches without it support only cardinality estimation.
+    fn has_minhash(&self) -> bool {
+        true
+    }
+}
+
+/// MurmurHash3 x64 128-bit. The default hash function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Murmur3;
+
+impl Murmur3 {
+    pub const ID: u8 = 0;
+}
+
+impl SketchHasher for Murmur3 {
+    fn id(&self) -> u8 {
+        Self::ID
+    }
+
+    fn hash(&self, element: &[u8]) -> u128 {
+        murmur3_x64_128(element, HASH_SEED)
+    }
+}
+
+/// XXH3 128-bit. Considerably faster than MurmurHash3 especially for long elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Xxh3;
+
+impl Xxh3 {
+    pub const ID: u8 = 1;
+}
+
+impl SketchHasher for Xxh3 {
+    fn id(&self) -> u8 {
+        Self::ID
+    }
+
+    fn hash(&self, element: &[u8]) -> u128 {
+        xxh3_128_with_seed(element, HASH_SEED)
+    }
+}
+
+/// Hash function of Redis built-in HyperLogLog. (MurmurHash64A)
+///
+/// Hashes are laid out so that elements are mapped to the same registers and pattern lengths as `PFADD`
+/// with default parameters, which makes sketches converted from HyperLogLog keep growing consistently.
+/// Only 64 bits are available, so MinHash-part is always zero. i.e. sketches support only cardinality estimation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RedisHll;
+
+impl RedisHll {
+    pub const ID: u8 = 2;
+    /// Seed of MurmurHash64A used by Redis.
+    pub const SEED: u64 = 0xadc83b19;
+    /// Number of bits to determine register in Redis. (HLL_P)
+    pub const P: usize = 14;
+}
+
+impl SketchHasher for RedisHll {
+    fn id(&self) -> u8 {
+        Self::ID
+    }
+
+    fn hash(&self, element: &[u8]) -> u128 {
+        let hash = murmur64a(element, Self::SEED);
+        let register = hash & ((1 << Self::P) - 1);
+        // Redis counts trailing zeros of the rest bits, while HyperMinHash counts leading zeros.
+        // Redis also caps the pattern length at (64 - P + 1) by setting the next bit, which is done by the sentinel.
+        let pattern = (hash >> Self::P).reverse_bits();
+        let sentinel = 1u128 << 63;
+
+        (register as u128) << (128 - Self::P) | (pattern as u128) << (64 - Self::P) | sentinel
+    }
+
+    fn has_minhash(&self) -> bool {
+        false
+    }
+}
+
+/// SketchHasher impl which determines hash function at runtime.
+/// e.g. by the identifier recorded in serialized sketches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Murmur3,
+    Xxh3,
+    /// Only for sketches converted from Redis built-in HyperLogLog. Not selectable by name.
+    RedisHll,
+}
+
+impl HashAlgorithm {
+    /// Returns None if the identifier is unknown.
+    pub fn from_id(id: u8) -> Option<HashAlgorithm> {
+        match id {
+            Murmur3::ID => Some(HashAlgorithm::Murmur3),
+            Xxh3::ID => Some(HashAlgorithm::Xxh3),
+            RedisHll::ID => Some(HashAlgorithm::RedisHll),
+            _ => None,
+        }
+    }
+
+    /// Returns None if the name is unknown. Case insensitive.
+    pub fn from_name(name: &[u8]) -> Option<HashAlgorithm> {
+        if name.eq_ignore_ascii_case(b"murmur3") {
+            Some(HashAlgorithm::Murmur3)
+        } else if name.eq_ignore_ascii_case(b"xxh3") {
+            Some(HashAlgorithm::Xxh3)
+        } else {
+            None
+        }
+    }
+}
+
+impl SketchHasher for HashAlgorithm {
+    fn id(&self) -> u8 {
+        match self {
+            HashAlgorithm::Murmur3 => Murmur3.id(),
+            HashAlgorithm::Xxh3 => Xxh3.id(),
+            HashAlgorithm::RedisHll => RedisHll.id(),
+        }
+    }
+
+    fn hash(&self, element: &[u8]) -> u128 {
+        match self {
+            HashAlgorithm::Murmur3 => Murmur3.hash(element),
+            HashAlgorithm::Xxh3 => Xxh3.hash(element),
+            HashAlgorithm::RedisHll => RedisHll.hash(element),
+        }
+    }
+
+    fn has_minhash(&self) -> bool {
+        match self {
+            HashAlgorithm::Murmur3 => Murmur3.has_minhash(),
+            HashAlgorithm::Xxh3 => Xxh3.has_minhash(),
+            HashAlgorithm::RedisHll => RedisHll.has_minhash(),
+        }
+    }
+}
 
 /// 128 bit version of MurmurHash3 for x64 architecture
 /// Original cpp implementation: https://github.com/aappleby/smhasher/blob/master/src/MurmurHash3.cpp
+#[allow(clippy::identity_op)]
 pub fn murmur3_x64_128(element: &[u8], seed: u64) -> u128 {
     let len = element.len();
     let nblocks = len / 16;
@@ -113,6 +266,44 @@ pub fn murmur3_x64_128(element: &[u8], seed: u64) -> u128 {
     (h1 as u128) << 64 | (h2 as u128)
 }
 
+/// 64 bit version of MurmurHash2 for x64 architecture, as used by Redis built-in HyperLogLog.
+/// Original c implementation: https://github.com/redis/redis/blob/unstable/src/hyperloglog.c
+pub fn murmur64a(element: &[u8], seed: u64) -> u64 {
+    let m = 0xc6a4a793_5bd1e995u64;
+    let r = 47;
+
+    let mut h = seed ^ (element.len() as u64).wrapping_mul(m);
+
+    let mut blocks = element.chunks_exact(8);
+    for block in &mut blocks {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(block);
+        let mut k = u64::from_le_bytes(buf);
+
+        k = k.wrapping_mul(m);
+        k ^= k >> r;
+        k = k.wrapping_mul(m);
+
+        h ^= k;
+        h = h.wrapping_mul(m);
+    }
+
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        for (i, &b) in tail.iter().enumerate() {
+            h ^= (b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(m);
+    }
+
+    h ^= h >> r;
+    h = h.wrapping_mul(m);
+    h ^= h >> r;
+
+    h
+}
+
+#[allow(clippy::unusual_byte_groupings)]
 fn fmix64(k: u64) -> u64 {
     let mut result = k;
 
@@ -127,7 +318,7 @@ fn fmix64(k: u64) -> u64 {
 
 #[cfg(test)]
 mod tests {
-    use super::murmur3_x64_128;
+    use super::*;
 
     #[test]
     fn test_hash() {
@@ -136,4 +327,43 @@ mod tests {
 
         assert_eq!(result, 0x6769dae0_ba0f9ccf_7e4bd221_908cfc07);
     }
+
+    #[test]
+    fn test_hash_algorithm() {
+        let element = "Lorem ipsum dolor sit amet, consectetur adipisicing elit".as_bytes();
+
+        assert_eq!(HashAlgorithm::Murmur3.hash(element), murmur3_x64_128(element, HASH_SEED));
+        assert_eq!(HashAlgorithm::Xxh3.hash(element), xxh3_128_with_seed(element, HASH_SEED));
+        assert_ne!(HashAlgorithm::Murmur3.hash(element), HashAlgorithm::Xxh3.hash(element));
+
+        assert_eq!(HashAlgorithm::from_id(Xxh3::ID), Some(HashAlgorithm::Xxh3));
+        assert_eq!(HashAlgorithm::from_id(RedisHll::ID), Some(HashAlgorithm::RedisHll));
+        assert_eq!(HashAlgorithm::from_id(3), None);
+        assert_eq!(HashAlgorithm::from_name(b"redishll"), None);
+        assert_eq!(HashAlgorithm::from_name(b"MURMUR3"), Some(HashAlgorithm::Murmur3));
+        assert_eq!(HashAlgorithm::from_name(b"xxh64"), None);
+    }
+
+    #[test]
+    fn test_redis_hll() {
+        assert_eq!(murmur64a(b"", 0), 0);
+        assert_ne!(murmur64a(b"a", RedisHll::SEED), murmur64a(b"b", RedisHll::SEED));
+
+        // register and pattern length are the same as Redis hllPatLen()
+        for i in 0..1000 {
+            let element = format!("id{}", i);
+            let hash = murmur64a(element.as_bytes(), RedisHll::SEED);
+            let register = (hash & ((1 << 14) - 1)) as u128;
+            let count = ((hash >> 14) | (1 << 50)).trailing_zeros() as u128 + 1;
+
+            let hash = RedisHll.hash(element.as_bytes());
+            assert_eq!(hash >> 114, register);
+            // pattern length is the number of leading zeros after the register bits plus one
+            assert_eq!((hash << 14).leading_zeros() as u128 + 1, count);
+            assert_eq!(hash & 0x3ff, 0);
+        }
+
+        assert!(!HashAlgorithm::RedisHll.has_minhash());
+        assert!(HashAlgorithm::Murmur3.has_minhash());
+    }
 }
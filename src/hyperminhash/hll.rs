@@ -0,0 +1,268 @@
+//! Conversion between HyperMinHash and Redis built-in HyperLogLog.
+//!
+//! Redis HyperLogLog string consists of 16-byte header and 2^14 6-bit registers.
+//!
+//! ```text
+//!  +------+---+-----+----------+
+//!  | HYLL | E | N/U | Cardin.  |
+//!  +------+---+-----+----------+
+//! ```
+//!
+//! - HYLL: 4 byte magic string.
+//! - E: 1 byte encoding. (DENSE or SPARSE)
+//! - N/U: 3 byte unused.
+//! - Cardin.: 8 byte cached cardinality in little endian. The most significant bit marks the cache invalid.
+//!   (ignored on conversion from HyperLogLog)
+//!
+//! In dense encoding, registers are packed into 6 bits each from the least significant bit of each byte.
+//! In sparse encoding, registers are run-length encoded by opcodes:
+//!
+//! - ZERO `00xxxxxx`: run of (xxxxxx + 1) empty registers.
+//! - XZERO `01xxxxxx yyyyyyyy`: run of (xxxxxxyyyyyyyy + 1) empty registers.
+//! - VAL `1vvvvvxx`: run of (xx + 1) registers of value (vvvvv + 1).
+//!
+//! Register value of Redis HyperLogLog is the same as the pattern length of HyperMinHash, as long as elements
+//! are hashed by `RedisHll`. So registers are converted into HyperMinHash registers with default parameters
+//! and empty MinHash-parts, which support only cardinality estimation.
+//! Conversely, pattern lengths of any sketch with P=14 are exported as dense HyperLogLog registers,
+//! capped to the maximum register value of HyperLogLog.
+//! See https://github.com/redis/redis/blob/unstable/src/hyperloglog.c for details.
+
+use super::*;
+use super::hash::{HashAlgorithm, RedisHll, SketchHasher};
+use super::sketch::HyperMinHash;
+
+const MAGIC: [u8; 4] = [b'H',b'Y',b'L',b'L'];
+const HEADER_LEN: usize = 16;
+const NUM_REGISTERS: usize = 1 << RedisHll::P;
+const REGISTER_BITS: usize = 6;
+const DENSE_LEN: usize = HEADER_LEN + (NUM_REGISTERS * REGISTER_BITS).div_ceil(8);
+
+const ENCODING_DENSE: u8 = 0;
+const ENCODING_SPARSE: u8 = 1;
+
+/// Maximum register value of HyperLogLog, i.e. HLL_Q + 1 where HLL_Q = 64 - P.
+const MAX_VALUE: u32 = 64 - RedisHll::P as u32 + 1;
+
+const OPCODE_ZERO: u8 = 0x00;
+const OPCODE_XZERO: u8 = 0x40;
+
+/// Returns true if bytes look like Redis HyperLogLog. (i.e. starts with the magic string)
+pub fn is_hll(bytes: &[u8]) -> bool {
+    bytes.len() >= MAGIC.len() && bytes[0..4] == MAGIC
+}
+
+impl HyperMinHash<ArrayRegisters, HashAlgorithm> {
+    /// Convert Redis HyperLogLog string into a sketch.
+    /// Returns None if given bytes are not a valid HyperLogLog.
+    pub fn from_hll(bytes: &[u8]) -> Option<Self> {
+        if !is_hll(bytes) || bytes.len() < HEADER_LEN {
+            return None;
+        }
+
+        let values = match bytes[4] {
+            ENCODING_DENSE if bytes.len() == DENSE_LEN => read_dense(&bytes[HEADER_LEN..]),
+            ENCODING_SPARSE => read_sparse(&bytes[HEADER_LEN..])?,
+            _ => return None,
+        };
+
+        let params = Params::default();
+        let registers = values.into_iter().map(|value| u32::from(value) << params.r()).collect();
+        Some(HyperMinHash::with_hasher(registers, params, HashAlgorithm::RedisHll))
+    }
+}
+
+impl <T : RegisterVector, H : SketchHasher> HyperMinHash<T, H> {
+    /// Export the sketch as Redis HyperLogLog string in dense encoding with valid cached cardinality.
+    /// Returns None unless the sketch has P=14, which is the fixed precision of HyperLogLog.
+    pub fn to_hll(&self) -> Option<Vec<u8>> {
+        let params = self.params();
+        if params.p() != RedisHll::P {
+            return None;
+        }
+
+        let values: Vec<u8> = (0..NUM_REGISTERS)
+            .map(|i| (self.registers.register_at(i) >> params.r()).min(MAX_VALUE) as u8)
+            .collect();
+
+        // estimate from capped values, so that the cache agrees with what PFCOUNT would compute
+        let capped: ArrayRegisters = values.iter().map(|&value| u32::from(value) << params.r()).collect();
+        let cardinality = HyperMinHash::with_params(capped, params).cardinality().round() as u64;
+
+        let mut bytes = Vec::with_capacity(DENSE_LEN);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&[ENCODING_DENSE, 0, 0, 0]);
+        // saturated estimate (infinity) is cast to u64::MAX, which marks the cache invalid and lets Redis recompute it
+        bytes.extend_from_slice(&cardinality.to_le_bytes());
+        write_dense(&values, &mut bytes);
+
+        Some(bytes)
+    }
+}
+
+fn read_dense(bytes: &[u8]) -> Vec<u8> {
+    (0..NUM_REGISTERS).map(|i| {
+        let byte = i * REGISTER_BITS / 8;
+        let shift = i * REGISTER_BITS % 8;
+        // the last register doesn't span the next byte
+        let next = bytes.get(byte + 1).cloned().unwrap_or(0);
+        let value = (u16::from(bytes[byte]) | u16::from(next) << 8) >> shift;
+        (value & ((1 << REGISTER_BITS) - 1)) as u8
+    }).collect()
+}
+
+fn write_dense(values: &[u8], bytes: &mut Vec<u8>) {
+    let start = bytes.len();
+    bytes.resize(start + (NUM_REGISTERS * REGISTER_BITS).div_ceil(8), 0);
+    for (i, &value) in values.iter().enumerate() {
+        let byte = start + i * REGISTER_BITS / 8;
+        let shift = i * REGISTER_BITS % 8;
+        let value = u16::from(value) << shift;
+        bytes[byte] |= value as u8;
+        // the last register doesn't span the next byte
+        if let Some(next) = bytes.get_mut(byte + 1) {
+            *next |= (value >> 8) as u8;
+        }
+    }
+}
+
+fn read_sparse(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut values = Vec::with_capacity(NUM_REGISTERS);
+    let mut i = 0;
+    while i < bytes.len() {
+        let opcode = bytes[i];
+        let (value, run) = match opcode & 0xc0 {
+            OPCODE_ZERO => (0, (opcode & 0x3f) as usize + 1),
+            OPCODE_XZERO => {
+                i += 1;
+                let low = *bytes.get(i)?;
+                (0, (((opcode & 0x3f) as usize) << 8 | low as usize) + 1)
+            },
+            // VAL
+            _ => (((opcode >> 2) & 0x1f) + 1, (opcode & 0x3) as usize + 1),
+        };
+        if values.len() + run > NUM_REGISTERS {
+            return None;
+        }
+        values.resize(values.len() + run, value);
+        i += 1;
+    }
+
+    if values.len() != NUM_REGISTERS {
+        return None;
+    }
+
+    Some(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(encoding: u8) -> Vec<u8> {
+        let mut bytes = b"HYLL".to_vec();
+        bytes.push(encoding);
+        bytes.extend_from_slice(&[0; 11]);
+        bytes
+    }
+
+    /// Same layout as HLL_DENSE_SET_REGISTER in Redis.
+    fn dense(values: &[u8]) -> Vec<u8> {
+        let mut bytes = header(ENCODING_DENSE);
+        bytes.resize(DENSE_LEN + 1, 0);
+        for (i, &value) in values.iter().enumerate() {
+            let byte = HEADER_LEN + i * 6 / 8;
+            let shift = i * 6 % 8;
+            bytes[byte] &= !(0x3f << shift);
+            bytes[byte] |= value << shift;
+            bytes[byte + 1] &= !(0x3fu16 >> (8 - shift)) as u8;
+            bytes[byte + 1] |= (u16::from(value) >> (8 - shift)) as u8;
+        }
+        bytes.truncate(DENSE_LEN);
+        bytes
+    }
+
+    #[test]
+    fn test_dense() {
+        let mut sketch = HyperMinHash::with_hasher(new_array_registers(&Params::default()), Params::default(), RedisHll);
+        for i in 0..100000 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        let values: Vec<u8> = sketch.registers.iter().map(|&reg| (reg >> R) as u8).collect();
+        // values wider than 4 bits span two bytes
+        assert!(values.iter().any(|&value| value >= 16));
+
+        let converted = HyperMinHash::from_hll(&dense(&values)).unwrap();
+        assert_eq!(converted.registers, sketch.registers);
+        assert_eq!(converted.params(), Params::default());
+        assert_eq!(converted.hasher(), &HashAlgorithm::RedisHll);
+        assert!(!converted.hasher().has_minhash());
+        assert!((converted.cardinality() - 100000.0).abs() < 100000.0 * 0.02);
+
+        let mut truncated = dense(&values);
+        truncated.pop();
+        assert!(HyperMinHash::from_hll(&truncated).is_none());
+    }
+
+    #[test]
+    fn test_sparse() {
+        // XZERO:1000, VAL:2 x 1, ZERO:3, VAL:32 x 4, XZERO:15376
+        let mut bytes = header(ENCODING_SPARSE);
+        bytes.extend_from_slice(&[0x40 | 0x03, 0xe7, 0x84, 0x02, 0xff, 0x40 | 0x3c, 0x0f]);
+
+        let sketch = HyperMinHash::from_hll(&bytes).unwrap();
+        let mut expected = new_array_registers(&Params::default());
+        expected[1000] = 2 << R;
+        expected[1004..1008].fill(32 << R);
+        assert_eq!(sketch.registers, expected);
+
+        // runs exceed the number of registers
+        let mut overflow = bytes.clone();
+        overflow.push(0x00);
+        assert!(HyperMinHash::from_hll(&overflow).is_none());
+
+        // runs don't fill registers
+        let mut short = bytes.clone();
+        short.truncate(short.len() - 2);
+        assert!(HyperMinHash::from_hll(&short).is_none());
+
+        // truncated XZERO
+        let mut truncated = bytes.clone();
+        truncated.pop();
+        assert!(HyperMinHash::from_hll(&truncated).is_none());
+
+        assert!(HyperMinHash::from_hll(b"HYMH").is_none());
+        assert!(HyperMinHash::from_hll(&header(2)).is_none());
+        assert!(is_hll(&bytes));
+        assert!(!is_hll(b"HYL"));
+    }
+
+    #[test]
+    fn test_to_hll() {
+        let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in 0..100000 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        // beyond the range of HyperLogLog registers
+        sketch.registers[0] = 60 << R | 1;
+
+        let bytes = sketch.to_hll().unwrap();
+        let mut values: Vec<u8> = sketch.registers.iter().map(|&reg| (reg >> R) as u8).collect();
+        values[0] = 51;
+        assert_eq!(bytes.len(), DENSE_LEN);
+        assert_eq!(&bytes[..8], b"HYLL\0\0\0\0");
+        assert_eq!(bytes[HEADER_LEN..], dense(&values)[HEADER_LEN..]);
+
+        let mut cached = [0u8; 8];
+        cached.copy_from_slice(&bytes[8..16]);
+        let cached = u64::from_le_bytes(cached);
+        assert!((cached as f64 - sketch.cardinality()).abs() < 100000.0 * 0.01);
+
+        let converted = HyperMinHash::from_hll(&bytes).unwrap();
+        let expected: Vec<u32> = values.iter().map(|&value| u32::from(value) << R).collect();
+        assert_eq!(converted.registers, expected);
+
+        let params = Params::new(12, Q, R).unwrap();
+        assert!(HyperMinHash::with_params(new_array_registers(&params), params).to_hll().is_none());
+    }
+}
@@ -0,0 +1,162 @@
+//! Interoperability with the serialization of LiveRamp HyperMinHash-java.
+//! See https://github.com/LiveRamp/HyperMinHash-java
+//!
+//! HyperMinHash-java serializes its `BetaMinHash` sketch, which has fixed parameters P=14, Q=6, R=10,
+//! as 1-byte version followed by 2^14 16-bit registers in big endian (Java's `ByteBuffer` default).
+//!
+//! ```text
+//!  +---+---------------------------+
+//!  | V | registers (2^14 × 2 byte) |
+//!  +---+---------------------------+
+//! ```
+//!
+//! Registers are packed in the same way as ours, i.e. `(pattern length << R) | rbits`,
+//! so they are converted one-to-one only between sketches with the same parameters.
+//! Both libraries hash elements by MurmurHash3 x64 128-bit, so imported sketches are regarded as built with `Murmur3`.
+
+use super::*;
+use super::hash::{Murmur3, SketchHasher};
+use super::sketch::HyperMinHash;
+
+const VERSION: u8 = 1;
+const REGISTER_BYTES: usize = 2;
+const LEN: usize = 1 + (1 << P) * REGISTER_BYTES;
+
+/// Parameters of `BetaMinHash`, which are the same as our default.
+fn params() -> Params {
+    Params::default()
+}
+
+impl HyperMinHash<ArrayRegisters> {
+    /// Deserialize `BetaMinHash` of HyperMinHash-java.
+    /// Returns None if given bytes are not a valid serialization.
+    pub fn from_liveramp(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != LEN || bytes[0] != VERSION {
+            return None;
+        }
+
+        let registers = bytes[1..].chunks(REGISTER_BYTES)
+            .map(|chunk| u32::from(chunk[0]) << 8 | u32::from(chunk[1]))
+            .collect();
+        Some(HyperMinHash::with_params(registers, params()))
+    }
+}
+
+impl <T : RegisterVector, H : SketchHasher> HyperMinHash<T, H> {
+    /// Serialize the sketch as `BetaMinHash` of HyperMinHash-java.
+    /// Fails unless the sketch has the parameters of `BetaMinHash` (P=14, Q=6, R=10) and is built with `Murmur3`.
+    pub fn to_liveramp(&self) -> Result<Vec<u8>, Error> {
+        if self.params() != params() {
+            return Err(Error::ParamsMismatch);
+        }
+        if self.hasher().id() != Murmur3::ID {
+            return Err(Error::HashMismatch);
+        }
+
+        let mut bytes = Vec::with_capacity(LEN);
+        bytes.push(VERSION);
+        for i in 0..self.params().num_registers() {
+            bytes.extend_from_slice(&(self.registers.register_at(i) as u16).to_be_bytes());
+        }
+
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::hash::Xxh3;
+
+    /// Serialization of `BetaMinHash` offered UTF-8 elements "id0" to "id999",
+    /// produced by HyperMinHash-java with testdata/liveramp/GoldenVectors.java.
+    const GOLDEN_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/liveramp/beta_minhash_1000.bin");
+
+    /// Layout-only vector: serialization of `new BetaMinHash()`.
+    fn golden_empty() -> Vec<u8> {
+        let mut bytes = vec![0u8; LEN];
+        bytes[0] = 0x01;
+        bytes
+    }
+
+    /// Layout-only vector: serialization of `BetaMinHash` whose registers are
+    /// 0: pattern length 1 and rbits 0x001, 1: pattern length 63 and rbits 0x3ff, 16383: pattern length 2 and rbits 0x3ff.
+    fn golden_registers() -> Vec<u8> {
+        let mut bytes = golden_empty();
+        bytes[1..5].copy_from_slice(&[0x04, 0x01, 0xff, 0xff]);
+        bytes[LEN - 2..].copy_from_slice(&[0x0b, 0xff]);
+        bytes
+    }
+
+    #[test]
+    fn test_golden() {
+        let sketch = HyperMinHash::from_liveramp(&golden_empty()).unwrap();
+        assert_eq!(sketch.registers, new_array_registers(&Params::default()));
+        assert_eq!(sketch.to_liveramp().unwrap(), golden_empty());
+
+        let sketch = HyperMinHash::from_liveramp(&golden_registers()).unwrap();
+        let mut expected = new_array_registers(&Params::default());
+        expected[0] = 1 << R | 0x001;
+        expected[1] = 63 << R | 0x3ff;
+        expected[16383] = 2 << R | 0x3ff;
+        assert_eq!(sketch.registers, expected);
+        assert_eq!(sketch.params(), Params::default());
+        assert_eq!(sketch.hasher(), &Murmur3);
+        assert_eq!(sketch.to_liveramp().unwrap(), golden_registers());
+    }
+
+    #[test]
+    #[ignore = "requires testdata/liveramp/beta_minhash_1000.bin generated by HyperMinHash-java"]
+    fn test_golden_java() {
+        let bytes = std::fs::read(GOLDEN_PATH).unwrap();
+        let imported = HyperMinHash::from_liveramp(&bytes).unwrap();
+
+        // same registers means that hash function, seed and bit selection agree
+        let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in 0..1000 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        assert_eq!(imported.registers, sketch.registers);
+        assert_eq!(sketch.to_liveramp().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in 0..10000 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+
+        let bytes = sketch.to_liveramp().unwrap();
+        assert_eq!(bytes.len(), 1 + 16384 * 2);
+        let imported = HyperMinHash::from_liveramp(&bytes).unwrap();
+        assert_eq!(imported.registers, sketch.registers);
+
+        // imported sketch can be merged into the sketch built by this library
+        let mut merged = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        merged.add(b"id0");
+        merged.merge(&imported).unwrap();
+        assert_eq!(merged.registers, sketch.registers);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut bytes = golden_empty();
+        bytes.pop();
+        assert!(HyperMinHash::from_liveramp(&bytes).is_none());
+
+        let mut bytes = golden_empty();
+        bytes[0] = 2;
+        assert!(HyperMinHash::from_liveramp(&bytes).is_none());
+
+        // parameters which BetaMinHash doesn't support
+        for (p, q, r) in [(12, 6, 10), (14, 5, 10), (14, 6, 8)] {
+            let params = Params::new(p, q, r).unwrap();
+            let sketch = HyperMinHash::with_params(new_array_registers(&params), params);
+            assert_eq!(sketch.to_liveramp(), Err(Error::ParamsMismatch));
+        }
+
+        let sketch = HyperMinHash::with_hasher(new_array_registers(&Params::default()), Params::default(), Xxh3);
+        assert_eq!(sketch.to_liveramp(), Err(Error::HashMismatch));
+    }
+}
@@ -0,0 +1,753 @@
+//! MinHash LSH (locality sensitive hashing) index over sketches.
+//!
+//! MinHash-parts (the low R bits of each register) of first `bands * rows` registers are
+//! divided into `bands` bands of `rows` registers. Sketches are indexed by the hash of each band,
+//! so that sketches sharing at least one band are found as candidates without scanning.
+//!
+//! Probability of a pair of Jaccard index J to be a candidate is `1 - (1 - J^rows)^bands`,
+//! which steeply rises around `(1 / bands)^(1 / rows)`.
+//!
+//! For containment search, the index also works as LSH Ensemble.
+//! (E. Zhu et al., "LSH Ensemble: Internet-Scale Domain Search", VLDB 2016)
+//! Sketches are partitioned by cardinality, and each band is also indexed by its prefixes of
+//! power-of-two lengths. Then the containment threshold is converted into the Jaccard threshold
+//! for the largest cardinality of each partition, and the number of bands and the prefix length
+//! are tuned for the threshold at query time.
+//!
+//! The index holds band hashes, cardinalities and copies of sketches at the time of insertion,
+//! so candidates can be re-ranked without accessing the sketches themselves.
+//! i.e. items have to be inserted again to reflect later updates of the sketch.
+//!
+//! Serialized format: (all integers are encoded in little endian)
+//!
+//! ```text
+//!  +------+---+-------+------+---+---+---+---+---+-------+---------+
+//!  | MHLS | V | Bands | Rows | S | P | Q | R | H | Count | Entries |
+//!  +------+---+-------+------+---+---+---+---+---+-------+---------+
+//! ```
+//!
+//! - MHLS: 4 byte magic string.
+//! - V: 1 byte format version.
+//! - Bands, Rows: 4 byte each.
+//! - S: 1 byte flag whether any sketch has been inserted. P, Q, R, H are meaningful only if set.
+//! - P, Q, R, H: 1 byte each parameters and hash function identifier of indexed sketches.
+//! - Count: 4 byte number of entries.
+//! - Entries: (4 byte key length, key, 8 byte cardinality as f64, 4 byte sketch length, sketch, band hashes) sorted by key.
+//!   Sketch is encoded by `HyperMinHash::to_bytes`.
+//!   Band hashes are ordered by band then prefix length, and each is 1 byte flag whether
+//!   the prefix is indexed followed by 8 byte hash.
+
+use super::*;
+use super::hash::{HashAlgorithm, SketchHasher};
+use super::sketch::{check_minhash, pairwise_similarity, HyperMinHash};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem::size_of;
+use xxhash_rust::xxh3::xxh3_64;
+
+const MAGIC: [u8; 4] = [b'M',b'H',b'L',b'S'];
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 22;
+
+/// Band value of empty registers, which never equals to any MinHash-part.
+const EMPTY_REGISTER: u32 = u32::MAX;
+
+/// Number of steps to integrate false positive and false negative probabilities.
+const INTEGRATION_STEPS: usize = 32;
+
+/// Weight of false negative probability against false positive on tuning query parameters.
+/// False negatives are weighted more, since false positives are excluded by re-ranking anyway.
+const FALSE_NEGATIVE_WEIGHT: f64 = 0.9;
+
+/// Ratio between adjacent numbers of bands searched on tuning query parameters.
+const BANDS_GRID_RATIO: f64 = 1.1;
+
+/// Number of rows per band of the temporary index used for clustering.
+const CLUSTER_ROWS: usize = 8;
+
+/// Errors which can occur while indexing sketches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LshError {
+    /// Sketch is not compatible with already indexed sketches.
+    Incompatible(Error),
+    /// Sketch has fewer registers than `bands * rows`.
+    TooFewRegisters,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    cardinality: f64,
+    /// Hash of each prefix of each band. None if all registers of the prefix are empty.
+    hashes: Vec<Option<u64>>,
+    /// Sketch encoded by `HyperMinHash::to_bytes`.
+    sketch: Vec<u8>,
+}
+
+/// Items keyed by cardinality partition and band hash.
+type Buckets = HashMap<(u32, u64), BTreeSet<Vec<u8>>>;
+
+#[derive(Clone)]
+pub struct LshIndex {
+    bands: usize,
+    rows: usize,
+    /// Indexed prefix lengths of each band. Powers of two less than rows, and rows.
+    levels: Vec<usize>,
+    /// Parameters and hash function identifier of indexed sketches, determined by the first inserted sketch.
+    sketch: Option<(Params, u8)>,
+    entries: BTreeMap<Vec<u8>, Entry>,
+    /// Items in each bucket keyed by cardinality partition and hash, for each prefix of each band.
+    buckets: Vec<Buckets>,
+    /// Number of items in each cardinality partition.
+    partitions: BTreeMap<u32, usize>,
+    /// Total length of indexed items and their sketches, kept for memory usage estimation.
+    item_bytes: usize,
+    sketch_bytes: usize,
+}
+
+impl LshIndex {
+    /// Returns None if `bands * rows` exceeds the number of registers of the largest sketch.
+    pub fn new(bands: usize, rows: usize) -> Option<LshIndex> {
+        if bands < 1 || rows < 1 || bands.saturating_mul(rows) > 1 << Params::MAX_P {
+            return None;
+        }
+
+        let mut levels: Vec<usize> = (0..).map(|i| 1 << i).take_while(|&len| len < rows).collect();
+        levels.push(rows);
+
+        Some(LshIndex {
+            bands,
+            rows,
+            buckets: vec![HashMap::new(); bands * levels.len()],
+            levels,
+            sketch: None,
+            entries: BTreeMap::new(),
+            partitions: BTreeMap::new(),
+            item_bytes: 0,
+            sketch_bytes: 0,
+        })
+    }
+
+    pub fn bands(&self) -> usize {
+        self.bands
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Number of indexed items.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Approximate memory usage in bytes, computed without traversing items.
+    /// Each item is held by the entry and by the bucket of each band hash, and its sketch by the entry.
+    pub fn mem_usage(&self) -> usize {
+        let num_hashes = self.bands * self.levels.len();
+        let num_buckets: usize = self.buckets.iter().map(|buckets| buckets.len()).sum();
+        let item = size_of::<Vec<u8>>();
+        let entries = self.entries.len() * (item + size_of::<Entry>() + num_hashes * size_of::<Option<u64>>());
+        let buckets = num_buckets * (size_of::<(u32, u64)>() + size_of::<BTreeSet<Vec<u8>>>());
+
+        size_of::<LshIndex>()
+            + entries + self.item_bytes + self.sketch_bytes
+            + buckets + num_hashes * (self.entries.len() * item + self.item_bytes)
+            + self.partitions.len() * size_of::<(u32, usize)>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.entries.contains_key(item)
+    }
+
+    /// Sketch of the item at the time of insertion. Returns None if the item is not indexed.
+    pub fn indexed_sketch(&self, item: &[u8]) -> Option<HyperMinHash<ArrayRegisters, HashAlgorithm>> {
+        self.entries.get(item).and_then(|entry| HyperMinHash::from_bytes(&entry.sketch))
+    }
+
+    /// Index the sketch as the item. Already indexed item is replaced.
+    /// Returns true if the item is newly indexed.
+    pub fn insert<T, H>(&mut self, item: &[u8], sketch: &HyperMinHash<T, H>) -> Result<bool, LshError>
+        where T : RegisterVector, H : SketchHasher {
+
+        let hashes = self.band_hashes(sketch)?;
+        if self.sketch.is_none() {
+            self.sketch = Some((sketch.params(), sketch.hasher().id()));
+        }
+
+        let inserted = !self.remove(item);
+        self.insert_entry(item.to_vec(), Entry { cardinality: sketch.cardinality(), hashes, sketch: sketch.to_bytes(), });
+
+        Ok(inserted)
+    }
+
+    fn insert_entry(&mut self, item: Vec<u8>, entry: Entry) {
+        let partition = partition(entry.cardinality);
+        for (i, hash) in entry.hashes.iter().enumerate() {
+            if let Some(hash) = hash {
+                self.buckets[i].entry((partition, *hash)).or_default().insert(item.clone());
+            }
+        }
+        *self.partitions.entry(partition).or_default() += 1;
+        self.item_bytes += item.len();
+        self.sketch_bytes += entry.sketch.len();
+        self.entries.insert(item, entry);
+    }
+
+    /// Returns true if the item was indexed.
+    pub fn remove(&mut self, item: &[u8]) -> bool {
+        let entry = match self.entries.remove(item) {
+            None => return false,
+            Some(entry) => entry,
+        };
+        self.item_bytes -= item.len();
+        self.sketch_bytes -= entry.sketch.len();
+
+        let partition = partition(entry.cardinality);
+        for (i, hash) in entry.hashes.iter().enumerate() {
+            if let Some(hash) = hash {
+                if let Some(bucket) = self.buckets[i].get_mut(&(partition, *hash)) {
+                    bucket.remove(item);
+                    if bucket.is_empty() {
+                        self.buckets[i].remove(&(partition, *hash));
+                    }
+                }
+            }
+        }
+        if let Some(count) = self.partitions.get_mut(&partition) {
+            *count -= 1;
+            if *count == 0 {
+                self.partitions.remove(&partition);
+            }
+        }
+
+        true
+    }
+
+    /// Items which share at least one band with the sketch, sorted.
+    pub fn query<T, H>(&self, sketch: &HyperMinHash<T, H>) -> Result<Vec<&[u8]>, LshError>
+        where T : RegisterVector, H : SketchHasher {
+
+        let hashes = self.band_hashes(sketch)?;
+
+        let mut result = BTreeSet::new();
+        for &partition in self.partitions.keys() {
+            self.collect(&hashes, partition, self.bands, self.levels.len() - 1, &mut result);
+        }
+
+        Ok(result.into_iter().collect())
+    }
+
+    /// Items which may contain at least `threshold` fraction of the sketch, sorted.
+    /// i.e. candidates of |Q∩X| / |Q| >= threshold, where Q is the sketch and X is an item.
+    pub fn query_containment<T, H>(&self, sketch: &HyperMinHash<T, H>, threshold: f64) -> Result<Vec<&[u8]>, LshError>
+        where T : RegisterVector, H : SketchHasher {
+
+        let hashes = self.band_hashes(sketch)?;
+        let q = sketch.cardinality();
+        if q <= 0.0 {
+            return Ok(vec![]);
+        }
+
+        let mut result = BTreeSet::new();
+        for &partition in self.partitions.keys() {
+            let upper = partition_upper_bound(partition);
+            // items smaller than threshold * q can't contain the sketch
+            if upper < threshold * q {
+                continue;
+            }
+
+            // the least Jaccard index of items in the partition which satisfy the containment threshold
+            let jaccard = threshold * q / (q + upper - threshold * q);
+            let (bands, level) = self.optimal_params(jaccard);
+            self.collect(&hashes, partition, bands, level, &mut result);
+        }
+
+        Ok(result.into_iter().collect())
+    }
+
+    /// Collect items sharing any of first `bands` bands at the prefix level in the partition.
+    fn collect<'a>(
+        &'a self,
+        hashes: &[Option<u64>],
+        partition: u32,
+        bands: usize,
+        level: usize,
+        result: &mut BTreeSet<&'a [u8]>) {
+
+        for band in 0..bands {
+            let i = band * self.levels.len() + level;
+            if let Some(bucket) = hashes[i].and_then(|hash| self.buckets[i].get(&(partition, hash))) {
+                result.extend(bucket.iter().map(|item| item.as_slice()));
+            }
+        }
+    }
+
+    /// Number of bands and prefix level which minimize the weighted sum of false positive and
+    /// false negative probabilities for the Jaccard threshold.
+    /// Number of bands is searched over a geometric grid to keep queries fast.
+    fn optimal_params(&self, threshold: f64) -> (usize, usize) {
+        // midpoints of integration steps in [0, threshold] and [threshold, 1], and their step widths
+        let nodes = |from: f64, to: f64| {
+            let step = (to - from) / INTEGRATION_STEPS as f64;
+            (0..INTEGRATION_STEPS).map(move |i| (from + (i as f64 + 0.5) * step, step))
+        };
+
+        let mut grid = vec![1];
+        while let Some(&last) = grid.last().filter(|&&last| last < self.bands) {
+            grid.push(((last as f64 * BANDS_GRID_RATIO) as usize).clamp(last + 1, self.bands));
+        }
+
+        let mut best = (self.bands, self.levels.len() - 1);
+        let mut min_error = f64::INFINITY;
+        for (level, &rows) in self.levels.iter().enumerate() {
+            // probability that a band doesn't match, for each integration node
+            let below: Vec<(f64, f64)> = nodes(0.0, threshold).map(|(x, step)| (1.0 - x.powi(rows as i32), step)).collect();
+            let above: Vec<(f64, f64)> = nodes(threshold, 1.0).map(|(x, step)| (1.0 - x.powi(rows as i32), step)).collect();
+
+            for &bands in grid.iter() {
+                let false_positive: f64 = below.iter().map(|&(miss, step)| (1.0 - miss.powi(bands as i32)) * step).sum();
+                let false_negative: f64 = above.iter().map(|&(miss, step)| miss.powi(bands as i32) * step).sum();
+                let error = (1.0 - FALSE_NEGATIVE_WEIGHT) * false_positive + FALSE_NEGATIVE_WEIGHT * false_negative;
+                if error < min_error {
+                    min_error = error;
+                    best = (bands, level);
+                }
+            }
+        }
+
+        best
+    }
+
+    fn band_hashes<T, H>(&self, sketch: &HyperMinHash<T, H>) -> Result<Vec<Option<u64>>, LshError>
+        where T : RegisterVector, H : SketchHasher {
+
+        let params = sketch.params();
+        match self.sketch {
+            Some((indexed, _)) if indexed != params =>
+                return Err(LshError::Incompatible(Error::ParamsMismatch)),
+            Some((_, hash_id)) if hash_id != sketch.hasher().id() =>
+                return Err(LshError::Incompatible(Error::HashMismatch)),
+            _ => {},
+        }
+        check_minhash(sketch.hasher()).map_err(LshError::Incompatible)?;
+        if self.bands * self.rows > params.num_registers() {
+            return Err(LshError::TooFewRegisters);
+        }
+
+        let mask = (1u32 << params.r()) - 1;
+        let mut hashes = Vec::with_capacity(self.bands * self.levels.len());
+        let mut band = Vec::with_capacity(self.rows * 4);
+        for b in 0..self.bands {
+            band.clear();
+            let mut empty = true;
+            for i in 0..self.rows {
+                let reg = sketch.registers.register_at(b * self.rows + i);
+                let value = if reg == 0 { EMPTY_REGISTER } else { reg & mask };
+                empty &= reg == 0;
+                band.extend_from_slice(&value.to_le_bytes());
+
+                if self.levels.contains(&(i + 1)) {
+                    hashes.push(if empty { None } else { Some(xxh3_64(&band)) });
+                }
+            }
+        }
+
+        Ok(hashes)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(HEADER_LEN);
+        result.extend_from_slice(&MAGIC);
+        result.push(VERSION);
+        result.extend_from_slice(&(self.bands as u32).to_le_bytes());
+        result.extend_from_slice(&(self.rows as u32).to_le_bytes());
+        match self.sketch {
+            None => result.extend_from_slice(&[0, 0, 0, 0, 0]),
+            Some((params, hash_id)) =>
+                result.extend_from_slice(&[1, params.p() as u8, params.q() as u8, params.r() as u8, hash_id]),
+        }
+        result.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+
+        for (item, entry) in self.entries.iter() {
+            result.extend_from_slice(&(item.len() as u32).to_le_bytes());
+            result.extend_from_slice(item);
+            result.extend_from_slice(&entry.cardinality.to_le_bytes());
+            result.extend_from_slice(&(entry.sketch.len() as u32).to_le_bytes());
+            result.extend_from_slice(&entry.sketch);
+            for hash in entry.hashes.iter() {
+                result.push(hash.is_some() as u8);
+                result.extend_from_slice(&hash.unwrap_or(0).to_le_bytes());
+            }
+        }
+
+        result
+    }
+
+    /// Returns None if given bytes are not a valid index.
+    pub fn from_bytes(bytes: &[u8]) -> Option<LshIndex> {
+        if bytes.len() < HEADER_LEN || bytes[0..4] != MAGIC || bytes[4] != VERSION {
+            return None;
+        }
+
+        let mut index = LshIndex::new(read_u32(&bytes[5..]) as usize, read_u32(&bytes[9..]) as usize)?;
+        index.sketch = match bytes[13] {
+            0 => None,
+            1 => Some((Params::new(bytes[14] as usize, bytes[15] as usize, bytes[16] as usize)?, bytes[17])),
+            _ => return None,
+        };
+        let count = read_u32(&bytes[18..]) as usize;
+        let num_hashes = index.bands * index.levels.len();
+
+        let mut rest = &bytes[HEADER_LEN..];
+        for _ in 0..count {
+            if rest.len() < 4 {
+                return None;
+            }
+            let len = read_u32(rest) as usize;
+            if rest.len() < 4 + len + 12 {
+                return None;
+            }
+            let sketch_len = read_u32(&rest[4 + len + 8..]) as usize;
+            let hashes_offset = 4 + len + 12 + sketch_len;
+            let entry_len = hashes_offset + num_hashes * 9;
+            if rest.len() < entry_len {
+                return None;
+            }
+
+            let item = rest[4..4 + len].to_vec();
+            let cardinality = f64::from_le_bytes(read_u64(&rest[4 + len..]));
+            if cardinality.is_nan() || cardinality < 0.0 {
+                return None;
+            }
+
+            // sketch has to be the one which the index accepts
+            let sketch = rest[4 + len + 12..hashes_offset].to_vec();
+            let decoded = HyperMinHash::from_bytes(&sketch)?;
+            if index.sketch != Some((decoded.params(), decoded.hasher().id())) {
+                return None;
+            }
+
+            let mut hashes = Vec::with_capacity(num_hashes);
+            for hash in rest[hashes_offset..entry_len].chunks(9) {
+                hashes.push(match hash[0] {
+                    0 => None,
+                    1 => Some(u64::from_le_bytes(read_u64(&hash[1..]))),
+                    _ => return None,
+                });
+            }
+
+            index.insert_entry(item, Entry { cardinality, hashes, sketch, });
+            rest = &rest[entry_len..];
+        }
+
+        if !rest.is_empty() || index.entries.len() != count {
+            return None;
+        }
+        Some(index)
+    }
+}
+
+/// Single-linkage clusters of sketches, i.e. connected components of the graph whose edges are
+/// pairs of estimated Jaccard index at least `threshold`.
+///
+/// Instead of comparing all pairs, candidate pairs are pruned by a temporary LSH index tuned for the threshold,
+/// so pairs above the threshold may rarely be missed.
+/// Each cluster is a sorted list of indices of `sketches`, and clusters are ordered by size descending
+/// then by the first index. `None` (e.g. non-existent keys) and empty sketches are singletons.
+pub fn cluster<T, H>(sketches: &[Option<&HyperMinHash<T, H>>], threshold: f64) -> Result<Vec<Vec<usize>>, Error>
+    where T : RegisterVector, H : SketchHasher {
+
+    let mut parents: Vec<usize> = (0..sketches.len()).collect();
+
+    if let Some(first) = sketches.iter().flatten().next() {
+        let params = first.params();
+        for sketch in sketches.iter().flatten() {
+            check_minhash(sketch.hasher())?;
+            if sketch.params() != params {
+                return Err(Error::ParamsMismatch);
+            }
+            if sketch.hasher().id() != first.hasher().id() {
+                return Err(Error::HashMismatch);
+            }
+        }
+
+        let rows = CLUSTER_ROWS.min(params.num_registers());
+        // index and band hashes never fail since bands * rows doesn't exceed the number of registers
+        let index = LshIndex::new(params.num_registers() / rows, rows).unwrap();
+        let (bands, level) = index.optimal_params(threshold);
+
+        let mut buckets: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+        let mut registers = Vec::with_capacity(sketches.len());
+        let mut cardinalities = Vec::with_capacity(sketches.len());
+        for (i, sketch) in sketches.iter().enumerate() {
+            match sketch {
+                None => {
+                    registers.push(vec![]);
+                    cardinalities.push(0.0);
+                },
+                Some(sketch) => {
+                    let hashes = index.band_hashes(*sketch).unwrap();
+                    for band in 0..bands {
+                        if let Some(hash) = hashes[band * index.levels.len() + level] {
+                            buckets.entry((band, hash)).or_default().push(i);
+                        }
+                    }
+                    registers.push((0..params.num_registers()).map(|j| sketch.registers.register_at(j)).collect());
+                    cardinalities.push(sketch.cardinality());
+                },
+            }
+        }
+
+        // same pair may share many bands, so the estimation is skipped once they are connected
+        for members in buckets.values() {
+            for (k, &a) in members.iter().enumerate() {
+                for &b in &members[k + 1..] {
+                    let (root_a, root_b) = (find_root(&mut parents, a), find_root(&mut parents, b));
+                    if root_a == root_b {
+                        continue;
+                    }
+                    let (similarity, _) =
+                        pairwise_similarity(&registers[a], &registers[b], cardinalities[a], cardinalities[b], &params);
+                    if similarity >= threshold {
+                        parents[root_a.max(root_b)] = root_a.min(root_b);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut clusters: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..sketches.len() {
+        let root = find_root(&mut parents, i);
+        clusters.entry(root).or_default().push(i);
+    }
+
+    let mut clusters: Vec<Vec<usize>> = clusters.into_values().collect();
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a[0].cmp(&b[0])));
+    Ok(clusters)
+}
+
+/// Root of the union-find tree with path halving.
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// Cardinality partition, which is (floor(log2(cardinality)) + 1) for cardinality >= 1 and 0 otherwise.
+fn partition(cardinality: f64) -> u32 {
+    if cardinality < 1.0 {
+        0
+    } else {
+        cardinality.log2().floor() as u32 + 1
+    }
+}
+
+fn partition_upper_bound(partition: u32) -> f64 {
+    2f64.powi(partition as i32)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[0..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8]) -> [u8; 8] {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[0..8]);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyperminhash::sketch::MinHashCombiner;
+
+    fn sketch(from: usize, to: usize) -> HyperMinHash<ArrayRegisters> {
+        let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in from..to {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        sketch
+    }
+
+    #[test]
+    fn test_query() {
+        assert!(LshIndex::new(0, 4).is_none());
+        assert!(LshIndex::new(1 << 15, 3).is_none());
+        assert_eq!(LshIndex::new(4, 8).unwrap().levels, vec![1, 2, 4, 8]);
+        assert_eq!(LshIndex::new(4, 5).unwrap().levels, vec![1, 2, 4, 5]);
+        assert_eq!(LshIndex::new(4, 1).unwrap().levels, vec![1]);
+
+        let mut index = LshIndex::new(32, 8).unwrap();
+        assert_eq!(index.insert(b"a", &sketch(0, 100000)), Ok(true));
+        // J = 0.9
+        assert_eq!(index.insert(b"b", &sketch(5000, 105000)), Ok(true));
+        // J = 0.1
+        assert_eq!(index.insert(b"c", &sketch(90000, 190000)), Ok(true));
+        assert_eq!(index.insert(b"d", &sketch(500000, 600000)), Ok(true));
+        assert_eq!(index.insert(b"d", &sketch(500000, 600000)), Ok(false));
+        assert_eq!(index.len(), 4);
+
+        assert_eq!(index.query(&sketch(0, 100000)), Ok(vec![&b"a"[..], &b"b"[..]]));
+
+        // index holds sketches at the time of insertion
+        assert_eq!(index.indexed_sketch(b"b").unwrap().registers, sketch(5000, 105000).registers);
+        index.insert(b"b", &sketch(5000, 106000)).unwrap();
+        assert_eq!(index.indexed_sketch(b"b").unwrap().registers, sketch(5000, 106000).registers);
+
+        assert!(index.remove(b"b"));
+        assert!(!index.remove(b"b"));
+        assert!(!index.contains(b"b"));
+        assert!(index.indexed_sketch(b"b").is_none());
+        assert_eq!(index.query(&sketch(0, 100000)), Ok(vec![&b"a"[..]]));
+
+        // empty sketch matches nothing
+        assert_eq!(index.query(&sketch(0, 0)), Ok(vec![]));
+
+        let small = Params::new(10, 6, 10).unwrap();
+        let other = HyperMinHash::with_params(new_array_registers(&small), small);
+        assert_eq!(index.insert(b"e", &other), Err(LshError::Incompatible(Error::ParamsMismatch)));
+
+        let mut index = LshIndex::new(512, 4).unwrap();
+        assert_eq!(index.insert(b"e", &other), Err(LshError::TooFewRegisters));
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn test_query_containment() {
+        let mut index = LshIndex::new(1024, 4).unwrap();
+
+        // large sets containing the query, whose Jaccard index to the query is small
+        for i in 0..5 {
+            let mut large = sketch(0, 5000);
+            for j in 0..(20000 * (i + 1)) {
+                large.add(format!("large{}_{}", i, j).as_bytes());
+            }
+            index.insert(format!("large{}", i).as_bytes(), &large).unwrap();
+        }
+        index.insert(b"similar", &sketch(0, 5500)).unwrap();
+        index.insert(b"disjoint", &sketch(100000, 200000)).unwrap();
+
+        let query = sketch(0, 5000);
+        let result = index.query_containment(&query, 0.8).unwrap();
+        for expected in [&b"large0"[..], b"large1", b"large2", b"large3", b"large4", b"similar"].iter() {
+            assert!(result.contains(expected), "{:?}", String::from_utf8_lossy(expected));
+        }
+        assert!(!result.contains(&&b"disjoint"[..]));
+
+        // candidates are re-ranked by containment against their indexed sketches
+        for item in [&b"large0"[..], b"large4"].iter() {
+            let mut combiner = MinHashCombiner::new();
+            combiner.combine(&query).unwrap();
+            combiner.combine(&index.indexed_sketch(item).unwrap()).unwrap();
+            assert!(combiner.intersection() / query.cardinality() > 0.8);
+        }
+
+        // Jaccard-oriented query can't find large sets
+        let result = index.query(&query).unwrap();
+        assert!(!result.contains(&&b"large4"[..]));
+
+        assert_eq!(index.query_containment(&sketch(0, 0), 0.8), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_optimal_params() {
+        let index = LshIndex::new(64, 8).unwrap();
+        // lower threshold prefers more bands and shorter prefix
+        let (bands, level) = index.optimal_params(0.01);
+        assert_eq!((bands, index.levels[level]), (64, 1));
+        let (bands, level) = index.optimal_params(0.9);
+        assert!(index.levels[level] > 1 && bands < 64);
+    }
+
+    #[test]
+    fn test_cluster() {
+        let sketches = [
+            sketch(0, 100000),
+            sketch(500000, 600000),
+            // J = 0.9 to 0
+            sketch(5000, 105000),
+            sketch(0, 0),
+            // J = 0.9 to 2, but 0.82 to 0
+            sketch(10000, 110000),
+            // J = 0.5 to 1
+            sketch(533333, 633333),
+        ];
+        let refs: Vec<_> = sketches.iter().map(Some).chain([None]).collect();
+
+        assert_eq!(cluster(&refs, 0.85), Ok(vec![vec![0, 2, 4], vec![1], vec![3], vec![5], vec![6]]));
+        assert_eq!(cluster(&refs, 0.4), Ok(vec![vec![0, 2, 4], vec![1, 5], vec![3], vec![6]]));
+        assert_eq!(cluster(&refs, 0.95), Ok((0..7).map(|i| vec![i]).collect()));
+
+        let none: Vec<Option<&HyperMinHash<ArrayRegisters>>> = vec![None, None];
+        assert_eq!(cluster(&none, 0.5), Ok(vec![vec![0], vec![1]]));
+
+        let small = Params::new(10, 6, 10).unwrap();
+        let other = HyperMinHash::with_params(new_array_registers(&small), small);
+        assert_eq!(cluster(&[Some(&sketches[0]), Some(&other)], 0.5), Err(Error::ParamsMismatch));
+    }
+
+    #[test]
+    fn test_to_from_bytes() {
+        let mut index = LshIndex::new(16, 4).unwrap();
+        let restored = LshIndex::from_bytes(&index.to_bytes()).unwrap();
+        assert!(restored.is_empty() && restored.sketch.is_none());
+
+        index.insert(b"a", &sketch(0, 100000)).unwrap();
+        index.insert(b"b", &sketch(0, 10)).unwrap();
+        index.insert(b"c", &sketch(500000, 600000)).unwrap();
+
+        let bytes = index.to_bytes();
+        let restored = LshIndex::from_bytes(&bytes).unwrap();
+        assert_eq!((restored.bands(), restored.rows()), (16, 4));
+        assert_eq!(restored.sketch, index.sketch);
+        assert_eq!(restored.entries, index.entries);
+        assert_eq!(restored.buckets, index.buckets);
+        assert_eq!(restored.partitions, index.partitions);
+        assert_eq!(restored.to_bytes(), bytes);
+        assert_eq!(restored.mem_usage(), index.mem_usage());
+        assert_eq!(restored.indexed_sketch(b"a").unwrap().registers, sketch(0, 100000).registers);
+
+        // sketch which the index doesn't accept
+        let mut other = LshIndex::new(16, 4).unwrap();
+        let small = Params::new(10, 6, 10).unwrap();
+        other.insert(b"a", &HyperMinHash::with_params(new_array_registers(&small), small)).unwrap();
+        let mut mismatched = other.to_bytes();
+        mismatched[14] = 14;
+        assert!(LshIndex::from_bytes(&mismatched).is_none());
+
+        assert!(LshIndex::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(LshIndex::from_bytes(&extra).is_none());
+        assert!(LshIndex::from_bytes(b"MHLS").is_none());
+    }
+
+    #[test]
+    fn test_mem_usage() {
+        let mut index = LshIndex::new(16, 4).unwrap();
+        let empty = index.mem_usage();
+
+        index.insert(b"a", &sketch(0, 100000)).unwrap();
+        let one = index.mem_usage();
+        assert!(one > empty);
+        index.insert(b"long-item-name", &sketch(0, 100000)).unwrap();
+        let two = index.mem_usage();
+        assert!(two > one);
+        // re-indexing doesn't grow
+        index.insert(b"a", &sketch(0, 100000)).unwrap();
+        assert_eq!(index.mem_usage(), two);
+
+        index.remove(b"long-item-name");
+        assert_eq!(index.mem_usage(), one);
+        index.remove(b"a");
+        assert_eq!(index.mem_usage(), empty);
+    }
+}
@@ -1,15 +1,129 @@
 //! Module contains Redis-independent HyperMinHash features.
 
+pub mod expr;
+pub mod hash;
+pub mod hll;
+pub mod liveramp;
+pub mod lsh;
+pub mod shingle;
+pub mod signature;
 pub mod sketch;
-mod hash;
+pub(crate) mod dense;
+// some functions are used only by the Redis module
+#[cfg_attr(not(feature = "redis-module"), allow(dead_code))]
+pub(crate) mod dma;
+#[cfg_attr(not(feature = "redis-module"), allow(dead_code))]
+pub(crate) mod repr;
+pub(crate) mod sparse;
 
 pub const HASH_BITS: usize = 128;
 pub const P: usize = 14;
 pub const Q: usize = 6;
 pub const R: usize = 10;
-pub const NUM_REGISTERS: usize = 1 << P;
-pub const HLL_Q: usize = 1 << Q;
-pub const HLL_BITS: usize = HASH_BITS - R;
+
+/// HyperMinHash parameters of a sketch.
+///
+/// - P: number of bits to determine register. (2^P registers)
+/// - Q: number of bits to store pattern length. (up to 2^Q leading zeros are counted, see `hll_q`)
+/// - R: number of bits to store MinHash-part.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    p: usize,
+    q: usize,
+    r: usize,
+}
+
+impl Params {
+    pub const MIN_P: usize = 4;
+    pub const MAX_P: usize = 16;
+    pub const MAX_Q: usize = 6;
+    /// Each register has to fit in 16-bit integer.
+    pub const MAX_REGISTER_BITS: usize = 16;
+
+    /// Returns None if given parameters are out of supported range.
+    pub fn new(p: usize, q: usize, r: usize) -> Option<Params> {
+        if !(Self::MIN_P..=Self::MAX_P).contains(&p) {
+            return None;
+        }
+        if !(1..=Self::MAX_Q).contains(&q) {
+            return None;
+        }
+        // at least one leading zero has to be counted. see `hll_q`
+        if r < 1 || q + r > Self::MAX_REGISTER_BITS || r + 2 > Self::MAX_REGISTER_BITS {
+            return None;
+        }
+
+        Some(Params { p, q, r, })
+    }
+
+    pub fn p(&self) -> usize {
+        self.p
+    }
+
+    pub fn q(&self) -> usize {
+        self.q
+    }
+
+    pub fn r(&self) -> usize {
+        self.r
+    }
+
+    pub fn num_registers(&self) -> usize {
+        1 << self.p
+    }
+
+    /// Maximum number of leading zeros counted for pattern length, i.e. pattern length is up to hll_q + 1.
+    /// It's 2^Q unless the pattern length exceeds 16 - R bits of the register, in which case it's capped to fit.
+    /// (e.g. 62 for default parameters, since pattern length 65 doesn't fit in 6 bits)
+    pub fn hll_q(&self) -> usize {
+        let max_pat_len = (1 << (Self::MAX_REGISTER_BITS - self.r)) - 1;
+        (1 << self.q).min(max_pat_len - 1)
+    }
+
+    /// Length of register histogram. i.e. the number of possible pattern lengths (including zero)
+    pub fn histogram_len(&self) -> usize {
+        self.hll_q() + 2
+    }
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params { p: P, q: Q, r: R, }
+    }
+}
+
+/// Cardinality estimation algorithms.
+/// Both are from Otmar Ertl, arXiv:1702.01284 "New cardinality estimation algorithms for HyperLogLog sketches"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Estimator {
+    /// Improved raw estimator. Same as Redis built-in HLL.
+    #[default]
+    Improved,
+    /// Maximum likelihood estimator. Slightly more accurate, but slower.
+    MaximumLikelihood,
+}
+
+/// Metrics of pairwise estimation between sketches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Jaccard index. |A∩B| / |A∪B|
+    Jaccard,
+    /// Intersection cardinality. |A∩B|
+    Intersection,
+    /// Fraction of a sketch contained in another. |A∩B| / |A|
+    Containment,
+}
+
+/// Errors which can occur while combining sketches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Sketches have different P,Q,R parameters.
+    ParamsMismatch,
+    /// Sketches are built with different hash functions.
+    HashMismatch,
+    /// Sketch has no MinHash-part, so it supports only cardinality estimation. (e.g. converted from Redis HyperLogLog)
+    NoMinHash,
+}
 
 /// Provides abstraction of HyperMinHash registers.
 pub trait RegisterVector {
@@ -18,10 +132,10 @@ pub trait RegisterVector {
     fn set_register(&mut self, idx: usize, value: u32);
 }
 
-pub type ArrayRegisters = [u32; NUM_REGISTERS];
+pub type ArrayRegisters = Vec<u32>;
 
-pub fn new_array_registers() -> ArrayRegisters {
-    [0u32; NUM_REGISTERS]
+pub fn new_array_registers(params: &Params) -> ArrayRegisters {
+    vec![0u32; params.num_registers()]
 }
 
 /// Plain array-backed RegisterVector impl.
@@ -34,3 +148,25 @@ impl RegisterVector for ArrayRegisters {
         self[idx] = value;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params() {
+        let params = Params::default();
+        assert_eq!(params.num_registers(), 16384);
+        assert_eq!(params.hll_q(), 62);
+        assert_eq!(Params::new(14, 4, 8).unwrap().hll_q(), 16);
+        assert_eq!(Params::new(14, 2, 14).unwrap().hll_q(), 2);
+
+        assert!(Params::new(10, 6, 10).is_some());
+        assert!(Params::new(3, 6, 10).is_none());
+        assert!(Params::new(17, 6, 10).is_none());
+        assert!(Params::new(14, 7, 9).is_none());
+        assert!(Params::new(14, 6, 11).is_none());
+        assert!(Params::new(14, 6, 0).is_none());
+        assert!(Params::new(14, 1, 15).is_none());
+    }
+}
@@ -0,0 +1,425 @@
+//! HyperMinHash data structure representation.
+//!
+//! The data structure is consists of 16-byte header and HyperMinHash registers.
+//!
+//! ## Header
+//!
+//! ```text
+//!  +------+---+---+----+---+----------+
+//!  | HYMH | E | P | QR | H | Cardin.  |
+//!  +------+---+---+----+---+----------+
+//! ```
+//!
+//! - HYMH: 4 byte magic string.
+//! - E: 1 byte flag represents register encoding. (DENSE or SPARSE)
+//!   The highest bit is set if the cached cardinality is an estimate given on creation
+//!   (e.g. by MH.INTERSECTSTORE) rather than computed from registers. See `ESTIMATED`.
+//! - P: 1 byte HyperMinHash P parameter.
+//! - QR: 1 byte HyperMinHash Q (upper 4 bits) and R (lower 4 bits) parameters.
+//! - H: 1 byte identifier of the hash function. (see `SketchHasher`)
+//! - Cardin.: 8 byte cached cardinality of the sketch
+//!
+//! P and QR are zero for sketches created before parameters became configurable.
+//! Such sketches are treated as having default parameters (P=14, Q=6, R=10).
+//! Likewise, H is zero (MurmurHash3) for sketches created before hash function became configurable.
+//!
+//! ## Registers
+//!
+//! Like Redis built-in HyperLogLog, sketches start with sparse representation
+//! and are promoted to dense representation once it becomes large.
+//!
+//! ### Dense representation
+//!
+//! In dense representation, registers are encoded as plain 16-bit integer array.
+//! Since registers are 16-bit wide, Q + R must not exceed 16.
+//!
+//! ### Sparse representation
+//!
+//! In sparse representation, only non-empty registers are encoded as
+//! a list of (16-bit register index, 16-bit register value) pairs sorted by register index.
+//!
+//! Sparse representation is promoted to dense when its size exceeds
+//! `SPARSE_MAX_BYTES` or the size of dense representation.
+//!
+//! ## Estimated cardinality
+//!
+//! Registers of some sketches don't represent their cardinality by themselves.
+//! e.g. the intersection sketch retains only registers equal across all inputs, so counting its registers
+//! underestimates the intersection. Such sketches pin the estimate given on creation as the cached cardinality.
+//! The estimate is discarded once registers are updated, as well as the cache.
+
+use super::dense::DenseVector;
+use super::dma::CByteArray;
+use super::sparse::SparseVector;
+use super::hash::{HashAlgorithm, SketchHasher};
+use super::{new_array_registers, ArrayRegisters, Error, Params, RegisterVector};
+use super::sketch::HyperMinHash;
+
+const MAGIC: [u8; 4] = [b'H',b'Y',b'M',b'H'];
+const HEADER_LEN: usize = 16;
+const SPARSE_MAX_BYTES: usize = 3000;
+
+pub enum Encoding {
+    Dense,
+    Sparse,
+}
+
+impl Encoding {
+    pub const DENSE: u8 = 0;
+    pub const SPARSE: u8 = 1;
+    /// Flag of E byte set if the cached cardinality is the estimate given on creation.
+    pub const ESTIMATED: u8 = 1 << 7;
+}
+
+pub enum Registers {
+    Dense(DenseVector),
+    Sparse(SparseVector),
+}
+
+impl RegisterVector for Registers {
+    fn register_at(&self, idx: usize) -> u32 {
+        match self {
+            Registers::Dense(registers) => registers.register_at(idx),
+            Registers::Sparse(registers) => registers.register_at(idx),
+        }
+    }
+
+    fn set_register(&mut self, idx: usize, value: u32) {
+        match self {
+            Registers::Dense(registers) => registers.set_register(idx, value),
+            Registers::Sparse(registers) => registers.set_register(idx, value),
+        }
+    }
+}
+
+pub struct HyperMinHashRepr {
+    encoding: Encoding,
+    params: Params,
+    hash: HashAlgorithm,
+    data: CByteArray,
+}
+
+impl HyperMinHashRepr {
+    pub fn dense_len(params: &Params) -> usize {
+        HEADER_LEN + DenseVector::dense_bytes(params)
+    }
+
+    pub fn sparse_len(num_entries: usize) -> usize {
+        HEADER_LEN + SparseVector::sparse_bytes(num_entries)
+    }
+
+    /// Returns true if given number of non-empty registers can be stored in sparse representation.
+    pub fn sparse_fits(params: &Params, num_entries: usize) -> bool {
+        let max_bytes = SPARSE_MAX_BYTES.min(DenseVector::dense_bytes(params));
+        SparseVector::sparse_bytes(num_entries) <= max_bytes
+    }
+
+    /// Initialize header of empty sparse sketch.
+    pub fn initialize<H : SketchHasher>(bytes: &mut CByteArray, params: &Params, hasher: &H) {
+        // set magic
+        for i in 0..4 {
+            bytes[i] = MAGIC[i]
+        }
+
+        bytes[4] = Encoding::SPARSE;
+        bytes[5] = params.p() as u8;
+        bytes[6] = (params.q() << 4 | params.r()) as u8;
+        bytes[7] = hasher.id();
+    }
+
+    pub fn parse(bytes: CByteArray) -> Option<HyperMinHashRepr> {
+        // check length
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+
+        // check magic
+        for i in 0..4 {
+            if bytes[i] != MAGIC[i] {
+                return None;
+            }
+        }
+
+        let params = if bytes[5] == 0 && bytes[6] == 0 {
+            Params::default()
+        } else {
+            Params::new(bytes[5] as usize, (bytes[6] >> 4) as usize, (bytes[6] & 0xf) as usize)?
+        };
+        let hash = HashAlgorithm::from_id(bytes[7])?;
+
+        match bytes[4] & !Encoding::ESTIMATED {
+            Encoding::DENSE if bytes.len() == Self::dense_len(&params) => {
+                Some(HyperMinHashRepr {
+                    encoding: Encoding::Dense,
+                    params,
+                    hash,
+                    data: bytes,
+                })
+            },
+            Encoding::SPARSE if SparseVector::validate(&bytes.offset(HEADER_LEN), &params) => {
+                Some(HyperMinHashRepr {
+                    encoding: Encoding::Sparse,
+                    params,
+                    hash,
+                    data: bytes,
+                })
+            },
+            _ => None,
+        }
+    }
+
+    pub fn params(&self) -> Params {
+        self.params
+    }
+
+    pub fn hash(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    /// Check that the sketch can be merged or compared with other sketch.
+    pub fn compatible(&self, other: &HyperMinHashRepr) -> Result<(), Error> {
+        if self.params != other.params {
+            Err(Error::ParamsMismatch)
+        } else if self.hash != other.hash {
+            Err(Error::HashMismatch)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        match self.encoding {
+            Encoding::Dense => Registers::Dense(
+                DenseVector::wrap(self.data.offset(HEADER_LEN))
+            ),
+            Encoding::Sparse => Registers::Sparse(
+                SparseVector::read(&self.data.offset(HEADER_LEN))
+            ),
+        }
+    }
+
+    pub fn sketch(&self) -> HyperMinHash<Registers, HashAlgorithm> {
+        HyperMinHash::with_hasher(self.registers(), self.params, self.hash)
+    }
+
+    /// Encode registers into a new byte array with appropriate representation.
+    /// Cached cardinality is marked as invalid.
+    pub fn to_vec<T : RegisterVector, H : SketchHasher>(params: &Params, hasher: &H, registers: &T) -> Vec<u8> {
+        let sparse = SparseVector::from_registers(params, registers);
+
+        let mut data = if Self::sparse_fits(params, sparse.len()) {
+            let mut data = vec![0u8; Self::sparse_len(sparse.len())];
+            let mut bytes = CByteArray::wrap(data.as_mut_ptr(), data.len());
+            Self::initialize(&mut bytes, params, hasher);
+            Self::write_sparse(&mut bytes, &sparse);
+            data
+        } else {
+            let mut data = vec![0u8; Self::dense_len(params)];
+            let mut bytes = CByteArray::wrap(data.as_mut_ptr(), data.len());
+            Self::initialize(&mut bytes, params, hasher);
+            Self::write_dense(&mut bytes, params, registers);
+            data
+        };
+        data[15] |= 1 << 7;
+
+        data
+    }
+
+    /// Write registers in sparse representation.
+    /// Bytes must be resized to `sparse_len` of the registers beforehand.
+    pub fn write_sparse(bytes: &mut CByteArray, registers: &SparseVector) {
+        bytes[4] = Encoding::SPARSE;
+        registers.write(&mut bytes.offset(HEADER_LEN));
+    }
+
+    /// Write registers in dense representation.
+    /// Bytes must be resized to `dense_len` beforehand.
+    pub fn write_dense<T : RegisterVector>(bytes: &mut CByteArray, params: &Params, registers: &T) {
+        bytes[4] = Encoding::DENSE;
+
+        let mut dense = DenseVector::wrap(bytes.offset(HEADER_LEN));
+        for i in 0..params.num_registers() {
+            dense.set_register(i, registers.register_at(i));
+        }
+    }
+
+    pub fn invalidate_cache(&mut self) {
+        self.data[4] &= !Encoding::ESTIMATED;
+        self.data[15] |= 1 << 7;
+    }
+
+    /// Returns true if the cached cardinality is the estimate given on creation,
+    /// which has to be used instead of counting registers.
+    pub fn estimated(&self) -> bool {
+        self.data[4] & Encoding::ESTIMATED != 0 && self.cache_valid()
+    }
+
+    /// Pin the estimate as the cardinality of the sketch until registers are updated.
+    pub fn set_estimate(&mut self, cardinality: u64) {
+        self.set_cache(cardinality);
+        self.data[4] |= Encoding::ESTIMATED;
+    }
+
+    pub fn cache_valid(&self) -> bool {
+        self.data[15] & (1 << 7) == 0
+    }
+
+    pub fn get_cache(&self) -> u64 {
+        let mut result = 0u64;
+
+
+        result |= u64::from(self.data[8]);
+        result |= u64::from(self.data[9]) << 8;
+        result |= u64::from(self.data[10]) << 16;
+        result |= u64::from(self.data[11]) << 24;
+        result |= u64::from(self.data[12]) << 32;
+        result |= u64::from(self.data[13]) << 40;
+        result |= u64::from(self.data[14]) << 48;
+        result |= u64::from(self.data[15]) << 56;
+
+        result
+    }
+
+    pub fn set_cache(&mut self, cardinality: u64) {
+        self.data[8] = (cardinality & 0xff) as u8;
+        self.data[9] = ((cardinality >> 8) & 0xff) as u8;
+        self.data[10] = ((cardinality >> 16) & 0xff) as u8;
+        self.data[11] = ((cardinality >> 24) & 0xff) as u8;
+        self.data[12] = ((cardinality >> 32) & 0xff) as u8;
+        self.data[13] = ((cardinality >> 40) & 0xff) as u8;
+        self.data[14] = ((cardinality >> 48) & 0xff) as u8;
+        self.data[15] = ((cardinality >> 56) & 0xff) as u8;
+    }
+}
+
+/// Conversion from/to the byte array stored by the Redis module.
+/// Bytes returned by `to_bytes` can be stored by plain `SET` and used by MH.* commands as is.
+impl HyperMinHash<ArrayRegisters, HashAlgorithm> {
+    /// Returns None if given bytes are not a valid sketch.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut data = bytes.to_vec();
+        let repr = HyperMinHashRepr::parse(CByteArray::wrap(data.as_mut_ptr(), data.len()))?;
+
+        let params = repr.params();
+        let registers = repr.registers();
+        let mut result = new_array_registers(&params);
+        for (i, register) in result.iter_mut().enumerate() {
+            *register = registers.register_at(i);
+        }
+
+        Some(HyperMinHash::with_hasher(result, params, repr.hash()))
+    }
+}
+
+impl <T : RegisterVector, H : SketchHasher> HyperMinHash<T, H> {
+    /// Note that the module accepts only the sketches built with hash functions in `HashAlgorithm`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        HyperMinHashRepr::to_vec(&self.params(), self.hasher(), &self.registers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyperminhash::hash::Xxh3;
+
+    #[test]
+    fn test_sparse_to_dense() {
+        let params = Params::new(4, 6, 10).unwrap();
+
+        let mut arr = vec![0u8; HyperMinHashRepr::sparse_len(0)];
+        let mut bytes = CByteArray::wrap(arr.as_mut_ptr(), arr.len());
+        HyperMinHashRepr::initialize(&mut bytes, &params, &HashAlgorithm::Murmur3);
+
+        let repr = HyperMinHashRepr::parse(bytes).unwrap();
+        assert_eq!(repr.params(), params);
+        let mut sketch = repr.sketch();
+        for i in 0..100 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        let registers = match sketch.registers {
+            Registers::Sparse(registers) => registers,
+            _ => panic!("expected sparse registers"),
+        };
+        assert_eq!(registers.len(), 16);
+        assert!(!HyperMinHashRepr::sparse_fits(&params, registers.len()));
+
+        arr.resize(HyperMinHashRepr::dense_len(&params), 0);
+        let mut bytes = CByteArray::wrap(arr.as_mut_ptr(), arr.len());
+        HyperMinHashRepr::write_dense(&mut bytes, &params, &registers);
+
+        let repr = HyperMinHashRepr::parse(bytes).unwrap();
+        match repr.registers() {
+            Registers::Dense(dense) => {
+                for i in 0..params.num_registers() {
+                    assert_eq!(dense.register_at(i), registers.register_at(i));
+                }
+            },
+            _ => panic!("expected dense registers"),
+        }
+    }
+
+    #[test]
+    fn test_estimate() {
+        let params = Params::new(10, 6, 10).unwrap();
+        let mut sketch = HyperMinHash::with_params(new_array_registers(&params), params);
+        for i in 0..10000 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        let mut arr = sketch.to_bytes();
+
+        let mut repr = HyperMinHashRepr::parse(CByteArray::wrap(arr.as_mut_ptr(), arr.len())).unwrap();
+        assert!(!repr.estimated());
+        repr.set_estimate(1234);
+        assert!(repr.estimated());
+        assert_eq!(repr.get_cache(), 1234);
+
+        // flag doesn't affect encoding
+        let mut repr = HyperMinHashRepr::parse(CByteArray::wrap(arr.as_mut_ptr(), arr.len())).unwrap();
+        assert!(repr.estimated());
+        assert!(matches!(repr.registers(), Registers::Dense(_)));
+
+        repr.invalidate_cache();
+        assert!(!repr.estimated());
+        assert_eq!(arr[4], Encoding::DENSE);
+    }
+
+    #[test]
+    fn test_to_from_bytes() {
+        let params = Params::new(10, 6, 10).unwrap();
+
+        let mut sketch = HyperMinHash::with_params(new_array_registers(&params), params);
+        for i in 0..100 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        let bytes = sketch.to_bytes();
+        assert_eq!(&bytes[0..4], b"HYMH");
+        assert_eq!(bytes[4], Encoding::SPARSE);
+
+        let restored = HyperMinHash::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.params(), params);
+        assert_eq!(restored.registers, sketch.registers);
+        assert_eq!(restored.cardinality(), sketch.cardinality());
+
+        for i in 0..10000 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        let bytes = sketch.to_bytes();
+        assert_eq!(bytes.len(), HyperMinHashRepr::dense_len(&params));
+        assert_eq!(bytes[4], Encoding::DENSE);
+        assert_eq!(HyperMinHash::from_bytes(&bytes).unwrap().registers, sketch.registers);
+
+        assert!(HyperMinHash::from_bytes(b"HYMH").is_none());
+
+        let mut sketch = HyperMinHash::with_hasher(new_array_registers(&params), params, Xxh3);
+        sketch.add(b"id");
+        let bytes = sketch.to_bytes();
+        assert_eq!(bytes[7], Xxh3::ID);
+        assert_eq!(HyperMinHash::from_bytes(&bytes).unwrap().hasher(), &HashAlgorithm::Xxh3);
+
+        // unknown hash function
+        let mut bytes = bytes;
+        bytes[7] = 0xff;
+        assert!(HyperMinHash::from_bytes(&bytes).is_none());
+    }
+}
@@ -0,0 +1,156 @@
+//! Text shingling for near-duplicate detection.
+//!
+//! Text is split into shingles (contiguous sequences of k words or k characters), each of which is added to
+//! a sketch as an element. Documents sharing most of their shingles have high Jaccard index between their sketches.
+//!
+//! - Words are maximal runs of alphanumeric characters. Shingles of words are joined by a single space.
+//! - Characters are Unicode scalar values of the text whose whitespace runs are collapsed into a single space and trimmed.
+//!
+//! Text shorter than k words or characters yields the whole text as a single shingle, and empty text yields none.
+//! Optionally text is lowercased and then normalized into Unicode Normalization Form C, so that canonically
+//! equivalent texts (e.g. precomposed "é" and "e" + combining acute accent) yield the same shingles.
+//! MH.ADDTEXT shingles text by the same `Shingler`, so sketches built offline agree with ones built on Redis.
+
+use super::hash::SketchHasher;
+use super::sketch::HyperMinHash;
+use super::RegisterVector;
+use unicode_normalization::UnicodeNormalization;
+
+/// Unit and length of shingles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shingle {
+    /// k contiguous words.
+    Word(usize),
+    /// k contiguous characters.
+    Char(usize),
+}
+
+impl Default for Shingle {
+    fn default() -> Self {
+        Shingle::Word(3)
+    }
+}
+
+/// Unicode normalization forms applied to text before splitting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Canonical decomposition followed by canonical composition.
+    Nfc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Shingler {
+    shingle: Shingle,
+    lowercase: bool,
+    normalization: Option<Normalization>,
+}
+
+impl Shingler {
+    /// If `lowercase` is true, text is lowercased before splitting.
+    /// If `normalization` is given, text is normalized (after lowercased) before splitting.
+    /// Returns None if k is zero.
+    pub fn new(shingle: Shingle, lowercase: bool, normalization: Option<Normalization>) -> Option<Shingler> {
+        match shingle {
+            Shingle::Word(0) | Shingle::Char(0) => None,
+            _ => Some(Shingler { shingle, lowercase, normalization, }),
+        }
+    }
+
+    pub fn shingles(&self, text: &str) -> Vec<String> {
+        let text = if self.lowercase { text.to_lowercase() } else { text.to_string() };
+        let text = match self.normalization {
+            None => text,
+            Some(Normalization::Nfc) => text.nfc().collect(),
+        };
+
+        match self.shingle {
+            Shingle::Word(k) => {
+                let words: Vec<&str> = text.split(|c: char| !c.is_alphanumeric())
+                    .filter(|word| !word.is_empty())
+                    .collect();
+                windows(&words, k).map(|window| window.join(" ")).collect()
+            },
+            Shingle::Char(k) => {
+                let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                let chars: Vec<char> = collapsed.chars().collect();
+                windows(&chars, k).map(|window| window.iter().collect()).collect()
+            },
+        }
+    }
+
+    /// Add shingles of the text to the sketch.
+    /// Returns true if any register is updated.
+    pub fn add_to<T : RegisterVector, H : SketchHasher>(&self, sketch: &mut HyperMinHash<T, H>, text: &str) -> bool {
+        let mut updated = false;
+        for shingle in self.shingles(text) {
+            updated |= sketch.add(shingle.as_bytes());
+        }
+        updated
+    }
+}
+
+/// Windows of k items, or the whole items as a single window if there are fewer than k items.
+fn windows<T>(items: &[T], k: usize) -> impl Iterator<Item = &[T]> {
+    let whole = if !items.is_empty() && items.len() < k { Some(items) } else { None };
+    items.windows(k).chain(whole)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyperminhash::{new_array_registers, Params};
+
+    #[test]
+    fn test_word() {
+        let shingler = Shingler::new(Shingle::Word(2), false, None).unwrap();
+        assert_eq!(shingler.shingles("The quick, brown  fox!"), vec!["The quick", "quick brown", "brown fox"]);
+        assert_eq!(shingler.shingles("  fox. "), vec!["fox"]);
+        assert!(shingler.shingles(" ... ").is_empty());
+
+        let shingler = Shingler::new(Shingle::Word(1), true, None).unwrap();
+        assert_eq!(shingler.shingles("Ünïcode TEXT"), vec!["ünïcode", "text"]);
+
+        assert_eq!(Shingler::default().shingles("a b c d"), vec!["a b c", "b c d"]);
+        assert!(Shingler::new(Shingle::Word(0), false, None).is_none());
+    }
+
+    #[test]
+    fn test_char() {
+        let shingler = Shingler::new(Shingle::Char(3), false, None).unwrap();
+        assert_eq!(shingler.shingles(" ab\n\t cd "), vec!["ab ", "b c", " cd"]);
+        assert_eq!(shingler.shingles("日本語"), vec!["日本語"]);
+        assert_eq!(shingler.shingles("ab"), vec!["ab"]);
+        assert!(shingler.shingles("   ").is_empty());
+        assert!(Shingler::new(Shingle::Char(0), true, None).is_none());
+    }
+
+    #[test]
+    fn test_normalization() {
+        // precomposed and decomposed "café"
+        let (composed, decomposed) = ("caf\u{e9} CAF\u{c9}", "cafe\u{301} CAFE\u{301}");
+
+        let shingler = Shingler::new(Shingle::Char(4), false, None).unwrap();
+        assert_ne!(shingler.shingles(composed), shingler.shingles(decomposed));
+
+        let shingler = Shingler::new(Shingle::Char(4), false, Some(Normalization::Nfc)).unwrap();
+        assert_eq!(shingler.shingles(decomposed), shingler.shingles(composed));
+        assert_eq!(shingler.shingles(decomposed)[0], "caf\u{e9}");
+
+        let shingler = Shingler::new(Shingle::Word(1), true, Some(Normalization::Nfc)).unwrap();
+        assert_eq!(shingler.shingles(decomposed), vec!["caf\u{e9}", "caf\u{e9}"]);
+    }
+
+    #[test]
+    fn test_add_to() {
+        let shingler = Shingler::new(Shingle::Word(2), true, None).unwrap();
+        let mut sketch1 = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        let mut sketch2 = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        assert!(shingler.add_to(&mut sketch1, "The quick brown fox"));
+        assert!(!shingler.add_to(&mut sketch1, "the QUICK brown fox."));
+
+        for shingle in ["the quick", "quick brown", "brown fox"] {
+            sketch2.add(shingle.as_bytes());
+        }
+        assert_eq!(sketch1.registers, sketch2.registers);
+    }
+}
@@ -0,0 +1,138 @@
+//! MinHash signature of a sketch.
+//!
+//! Signature is a fixed-length vector of the MinHash-parts (the low R bits) of registers, one per register.
+//! Two sketches agree on a position with the probability of their Jaccard index (plus chance collisions),
+//! so signatures can be fed into models for embedding or near-duplicate detection as they are.
+//!
+//! Signature can be compressed by b-bit minwise hashing (Ping Li and Arnd Christian König, arXiv:0910.3349),
+//! which keeps only the lowest b bits of each value.
+
+use super::*;
+use super::hash::SketchHasher;
+use super::sketch::{check_minhash, HyperMinHash};
+
+/// MinHash signature. Empty registers have no value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    values: Vec<Option<u32>>,
+    bits: usize,
+}
+
+impl Signature {
+    /// Values in the order of registers. None for empty registers.
+    pub fn values(&self) -> &[Option<u32>] {
+        &self.values
+    }
+
+    /// Width of each value in bits.
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    /// Compress the signature by b-bit minwise hashing.
+    /// Returns None unless b is in 1..=bits.
+    pub fn compress(&self, b: usize) -> Option<Signature> {
+        if b < 1 || b > self.bits {
+            return None;
+        }
+
+        let mask = (1u32 << b) - 1;
+        let values = self.values.iter().map(|value| value.map(|value| value & mask)).collect();
+        Some(Signature { values, bits: b, })
+    }
+
+    /// Pack values into bytes, `bits` bits each, from the most significant bit of each byte.
+    /// Empty registers are packed as zero. The last byte is padded with zero bits.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; (self.values.len() * self.bits).div_ceil(8)];
+        for (i, value) in self.values.iter().enumerate() {
+            let value = value.unwrap_or(0);
+            for j in 0..self.bits {
+                if value & (1 << (self.bits - 1 - j)) != 0 {
+                    let bit = i * self.bits + j;
+                    bytes[bit / 8] |= 0x80 >> (bit % 8);
+                }
+            }
+        }
+
+        bytes
+    }
+}
+
+impl <T : RegisterVector, H : SketchHasher> HyperMinHash<T, H> {
+    /// MinHash signature of the sketch, i.e. the low R bits of each non-empty register.
+    /// Fails if the sketch has no MinHash-part.
+    pub fn signature(&self) -> Result<Signature, Error> {
+        check_minhash(self.hasher())?;
+
+        let params = self.params();
+        let mask = (1u32 << params.r()) - 1;
+        let values = (0..params.num_registers()).map(|i| {
+            let reg = self.registers.register_at(i);
+            if reg == 0 { None } else { Some(reg & mask) }
+        }).collect();
+
+        Ok(Signature { values, bits: params.r(), })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyperminhash::hash::RedisHll;
+
+    #[test]
+    fn test_signature() {
+        let params = Params::new(4, 6, 10).unwrap();
+        let mut registers = new_array_registers(&params);
+        registers[0] = 1 << 10 | 0x3a5;
+        registers[2] = 5 << 10;
+        let sketch = HyperMinHash::with_params(registers, params);
+
+        let signature = sketch.signature().unwrap();
+        assert_eq!(signature.bits(), 10);
+        assert_eq!(signature.values().len(), 16);
+        assert_eq!(signature.values()[0], Some(0x3a5));
+        assert_eq!(signature.values()[1], None);
+        assert_eq!(signature.values()[2], Some(0));
+
+        let bytes = signature.to_bytes();
+        assert_eq!(bytes.len(), 20);
+        // 1110100101 0000000000 0000000000 ...
+        assert_eq!(&bytes[..4], &[0xe9, 0x40, 0x00, 0x00]);
+        assert!(bytes[4..].iter().all(|&byte| byte == 0));
+
+        let compressed = signature.compress(3).unwrap();
+        assert_eq!(compressed.bits(), 3);
+        assert_eq!(compressed.values()[0], Some(0x5));
+        assert_eq!(compressed.values()[1], None);
+        // 101 000 000 ... padded to 6 bytes
+        assert_eq!(compressed.to_bytes().len(), 6);
+        assert_eq!(compressed.to_bytes()[0], 0xa0);
+
+        assert!(signature.compress(0).is_none());
+        assert!(signature.compress(11).is_none());
+    }
+
+    #[test]
+    fn test_signature_similarity() {
+        let mut sketch1 = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        let mut sketch2 = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in 0..100000 {
+            sketch1.add(format!("id{}", i).as_bytes());
+            sketch2.add(format!("id{}", i + 50000).as_bytes());
+        }
+
+        // Jaccard index is 1/3
+        let signature1 = sketch1.signature().unwrap();
+        let signature2 = sketch2.signature().unwrap();
+        let matched = signature1.values().iter().zip(signature2.values())
+            .filter(|(a, b)| a.is_some() && a == b)
+            .count();
+        let similarity = matched as f64 / signature1.values().len() as f64;
+        assert!((similarity - 1.0 / 3.0).abs() < 0.02);
+
+        let sketch = HyperMinHash::with_hasher(new_array_registers(&Params::default()), Params::default(), RedisHll);
+        assert_eq!(sketch.signature(), Err(Error::NoMinHash));
+    }
+}
@@ -1,45 +1,105 @@
 //! HyperMinHash data structure.
 
 use super::*;
-use super::hash::murmur3_x64_128;
+use super::hash::{Murmur3, SketchHasher};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+pub use super::hash::HASH_SEED;
 
 /// constant for 0.5/ln(2)
-const HLL_ALPHA_INF: f64 = 0.721347520444481703680;
-const HASH_SEED: u64 = 0x1fb03e03;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
 
 /// Represents HyperMinHash sketch
-pub struct HyperMinHash<T : RegisterVector> {
+pub struct HyperMinHash<T : RegisterVector, H : SketchHasher = Murmur3> {
     pub registers: T,
+    params: Params,
+    hasher: H,
+}
+
+impl <T : RegisterVector> HyperMinHash<T> {
+    /// Wrap registers as a sketch with default parameters.
+    pub fn wrap(registers: T) -> Self {
+        Self::with_params(registers, Params::default())
+    }
+
+    pub fn with_params(registers: T, params: Params) -> Self {
+        Self::with_hasher(registers, params, Murmur3)
+    }
 }
 
 /// HyperLogLog-part of HyperMinHash.
 /// Cardinality estimation is based on Otmar Ertl, arXiv:1702.01284 "New cardinality estimation algorithms for HyperLogLog sketches"
 /// which is adopted in Redis.
-impl <T : RegisterVector> HyperMinHash<T> {
-    pub fn wrap(registers: T) -> Self {
-        Self { registers, }
+impl <T : RegisterVector, H : SketchHasher> HyperMinHash<T, H> {
+    pub fn with_hasher(registers: T, params: Params, hasher: H) -> Self {
+        Self { registers, params, hasher, }
+    }
+
+    pub fn params(&self) -> Params {
+        self.params
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
     }
 
     /// Merge given sketch into this sketch destructively.
-    pub fn merge<U : RegisterVector>(&mut self, other: &HyperMinHash<U>) {
-        for i in 0..NUM_REGISTERS {
+    pub fn merge<U : RegisterVector, G : SketchHasher>(&mut self, other: &HyperMinHash<U, G>) -> Result<(), Error> {
+        if self.params != other.params {
+            return Err(Error::ParamsMismatch);
+        }
+        if self.hasher.id() != other.hasher.id() {
+            return Err(Error::HashMismatch);
+        }
+
+        for i in 0..self.params.num_registers() {
             let reg = other.registers.register_at(i);
             if reg > self.registers.register_at(i) {
                 self.registers.set_register(i, reg);
             }
         }
+
+        Ok(())
+    }
+
+    /// Merge only HyperLogLog-parts (pattern lengths) of given sketch into this sketch destructively,
+    /// regardless of hash functions. MinHash-parts of this sketch are cleared, so it supports only cardinality estimation.
+    ///
+    /// This is an approximation for sketches built with different hash functions (e.g. converted from Redis HyperLogLog).
+    /// Since an element is hashed differently by each sketch, elements in both sketches are counted twice.
+    /// Hence the union cardinality is exact (up to estimation error) only for disjoint sets, and overestimated otherwise.
+    pub fn merge_cardinality<U : RegisterVector, G : SketchHasher>(&mut self, other: &HyperMinHash<U, G>) -> Result<(), Error> {
+        if self.params != other.params {
+            return Err(Error::ParamsMismatch);
+        }
+
+        let r = self.params.r();
+        for i in 0..self.params.num_registers() {
+            let pat_len = (self.registers.register_at(i) >> r).max(other.registers.register_at(i) >> r);
+            self.registers.set_register(i, pat_len << r);
+        }
+
+        Ok(())
     }
 
     pub fn add(&mut self, element: &[u8]) -> bool {
-        let hash = murmur3_x64_128(element, HASH_SEED);
+        let hash = self.hasher.hash(element);
+        self.add_hash(hash)
+    }
 
-        let PatLen { register, len: pat_len } = pat_len(&hash);
+    /// Add precomputed 128-bit hash of an element.
+    /// The hash should be computed by the same hash function as the sketch's hasher
+    /// so that the sketch can be merged or compared with other sketches.
+    pub fn add_hash(&mut self, hash: u128) -> bool {
+        let PatLen { register, len: pat_len } = pat_len(&hash, &self.params);
 
         // take rightmost R bits
-        let r_mask = ((1 << R) - 1) as u128;
+        let r = self.params.r();
+        let r_mask = ((1 << r) - 1) as u128;
         let rbits = hash & r_mask;
 
-        let packed = rbits as u32 | (pat_len << R as u32);
+        let packed = rbits as u32 | (pat_len << r as u32);
         if packed > self.registers.register_at(register) {
             self.registers.set_register(register, packed);
             return true
@@ -49,38 +109,66 @@ impl <T : RegisterVector> HyperMinHash<T> {
     }
 
     pub fn cardinality(&self) -> f64 {
-        let mut reg_histo = [0u32; HLL_BITS];
-        for i in 0..NUM_REGISTERS {
-            reg_histo[self.registers.register_at(i) as usize >> R] += 1;
+        self.cardinality_with(Estimator::Improved)
+    }
+
+    pub fn cardinality_with(&self, estimator: Estimator) -> f64 {
+        let mut reg_histo = vec![0u32; self.params.histogram_len()];
+        for i in 0..self.params.num_registers() {
+            reg_histo[self.registers.register_at(i) as usize >> self.params.r()] += 1;
         }
 
-        cardinality(&reg_histo)
+        match estimator {
+            Estimator::Improved => cardinality(&reg_histo, &self.params),
+            Estimator::MaximumLikelihood => ml_cardinality(&reg_histo, &self.params),
+        }
     }
 }
 
 /// MinHash-part of HyperMinHash.
 /// Combines multiple sketches, estimate their similarity and intersection cardinality.
+///
+/// Parameters and hash function of the combiner are determined by the first combined sketch.
 pub struct MinHashCombiner {
     union: HyperMinHash<ArrayRegisters>,
     reg_intersection: ArrayRegisters,
     cardinalities: Vec<f64>,
+    hash_id: u8,
 }
 
 impl MinHashCombiner {
     pub fn new() -> MinHashCombiner {
+        let params = Params::default();
+
         Self {
-            union: HyperMinHash::wrap(new_array_registers()),
-            reg_intersection: new_array_registers(),
+            union: HyperMinHash::wrap(new_array_registers(&params)),
+            reg_intersection: new_array_registers(&params),
             cardinalities: Vec::new(),
+            hash_id: Murmur3::ID,
         }
     }
 
-    pub fn combine<T : RegisterVector>(&mut self, sketch: &HyperMinHash<T>) {
+    pub fn combine<T : RegisterVector, H : SketchHasher>(&mut self, sketch: &HyperMinHash<T, H>) -> Result<(), Error> {
         // number of sketches merged so far
         let num_sketch = self.cardinalities.len();
-        let mut reg_histo = [0u32; HLL_BITS];
+        let params = sketch.params;
+        check_minhash(&sketch.hasher)?;
+
+        if num_sketch < 1 {
+            if self.union.params != params {
+                self.union = HyperMinHash::with_params(new_array_registers(&params), params);
+                self.reg_intersection = new_array_registers(&params);
+            }
+            self.hash_id = sketch.hasher.id();
+        } else if self.union.params != params {
+            return Err(Error::ParamsMismatch);
+        } else if self.hash_id != sketch.hasher.id() {
+            return Err(Error::HashMismatch);
+        }
+
+        let mut reg_histo = vec![0u32; params.histogram_len()];
 
-        for i in 0..NUM_REGISTERS {
+        for i in 0..params.num_registers() {
             let reg = sketch.registers.register_at(i);
 
             // merge into self
@@ -89,7 +177,7 @@ impl MinHashCombiner {
             }
 
             // update reg_histo for cardinality estimation
-            reg_histo[reg as usize >> R] += 1;
+            reg_histo[reg as usize >> params.r()] += 1;
 
             // update reg_intersection for similarity estimation
             // retain only if register values are equal
@@ -100,7 +188,9 @@ impl MinHashCombiner {
             }
         }
 
-        self.cardinalities.push(cardinality(&reg_histo))
+        self.cardinalities.push(cardinality(&reg_histo, &params));
+
+        Ok(())
     }
 
     pub fn similarity(&self) -> f64 {
@@ -112,6 +202,94 @@ impl MinHashCombiner {
             return 1.0;
         }
 
+        let (c, n) = self.matched_registers();
+        if c == 0 {
+            return 0.0;
+        }
+
+        // expected collision computation in original paper (algorithm 2.1.5) is only for 2 sets.
+        // for 3 or more sets, use its generalization.
+        // see discussion in: https://github.com/LiveRamp/HyperMinHash-java/issues/13
+        let ec = if self.cardinalities.len() == 2 {
+            approx_expected_collision(self.cardinalities[0], self.cardinalities[1], &self.union.params)
+        } else {
+            approx_expected_collision_k(&self.cardinalities, &self.union.params)
+        };
+        (c as f64 - ec) / n as f64
+    }
+
+    pub fn intersection(&self) -> f64 {
+        self.similarity() * self.union.cardinality()
+    }
+
+    /// Parameters of combined sketches.
+    pub fn params(&self) -> Params {
+        self.union.params
+    }
+
+    /// Registers equal across all combined sketches and zero otherwise, which form the approximate intersection sketch.
+    /// Since only a fraction of registers is retained, counting them underestimates the intersection.
+    /// Use `intersection` for its cardinality.
+    pub fn intersection_registers(&self) -> &ArrayRegisters {
+        &self.reg_intersection
+    }
+
+    /// All set-overlap metrics of combined sketches at once.
+    pub fn estimates(&self) -> Estimates {
+        let similarity = self.similarity();
+        let union = self.union.cardinality();
+        let intersection = similarity * union;
+
+        let ratio = |denominator: f64| if denominator > 0.0 { intersection / denominator } else { 0.0 };
+        let total: f64 = self.cardinalities.iter().sum();
+        let smallest = self.cardinalities.iter().cloned().fold(f64::INFINITY, f64::min);
+
+        Estimates {
+            cardinalities: self.cardinalities.clone(),
+            union,
+            similarity,
+            intersection,
+            containments: self.cardinalities.iter().map(|&n| ratio(n)).collect(),
+            dice: ratio(total / self.cardinalities.len() as f64),
+            overlap: ratio(smallest),
+        }
+    }
+
+    /// Confidence interval of the similarity at given confidence level. (e.g. 0.95)
+    /// Matched registers are regarded as binomial samples out of non-empty union registers,
+    /// so the variance is J(1-J)/n.
+    pub fn similarity_interval(&self, confidence: f64) -> ConfidenceInterval {
+        let similarity = self.similarity();
+        let (_, n) = self.matched_registers();
+        if self.cardinalities.len() < 2 || n == 0 {
+            return ConfidenceInterval::exact(similarity);
+        }
+
+        let j = similarity.clamp(0.0, 1.0);
+        let error = normal_quantile(confidence) * (j * (1.0 - j) / n as f64).sqrt();
+
+        ConfidenceInterval {
+            estimate: similarity,
+            lower: (j - error).max(0.0),
+            upper: (j + error).min(1.0),
+        }
+    }
+
+    /// Confidence interval of the intersection cardinality at given confidence level. (e.g. 0.95)
+    /// Errors of the similarity and the union cardinality are propagated by delta method.
+    pub fn intersection_interval(&self, confidence: f64) -> ConfidenceInterval {
+        let similarity = self.similarity();
+        let union = self.union.cardinality();
+        let (_, n) = self.matched_registers();
+        if self.cardinalities.len() < 2 || n == 0 {
+            return ConfidenceInterval::exact(similarity * union);
+        }
+
+        ConfidenceInterval::fraction_of_union(similarity, n, union, &self.union.params, confidence)
+    }
+
+    /// Number of registers matched among all sketches, and number of non-empty union registers.
+    fn matched_registers(&self) -> (u64, u64) {
         let mut c = 0u64;
         let mut n = 0u64;
 
@@ -124,29 +302,328 @@ impl MinHashCombiner {
             }
         }
 
-        if c == 0 {
-            return 0.0;
+        (c, n)
+    }
+}
+
+/// Pairwise estimation among sketches, as a flat N×N row-major array.
+/// For containment, (i, j) element is the fraction of i-th sketch contained in j-th sketch.
+///
+/// Registers and cardinality of each sketch are loaded only once, so this is much faster
+/// than combining each pair by MinHashCombiner. `None` is regarded as an empty set.
+pub fn pairwise_matrix<T, H>(sketches: &[Option<&HyperMinHash<T, H>>], metric: Metric) -> Result<Vec<f64>, Error>
+    where T : RegisterVector, H : SketchHasher {
+
+    let mut first: Option<(Params, u8)> = None;
+    for sketch in sketches.iter().flatten() {
+        check_minhash(&sketch.hasher)?;
+        match first {
+            None => first = Some((sketch.params, sketch.hasher.id())),
+            Some((params, _)) if params != sketch.params => return Err(Error::ParamsMismatch),
+            Some((_, hash_id)) if hash_id != sketch.hasher.id() => return Err(Error::HashMismatch),
+            _ => {},
+        }
+    }
+    let params = first.map(|(params, _)| params).unwrap_or_default();
+
+    let registers: Vec<Option<ArrayRegisters>> = sketches.iter()
+        .map(|sketch| sketch.map(|sketch| {
+            (0..params.num_registers()).map(|i| sketch.registers.register_at(i)).collect()
+        }))
+        .collect();
+    let cardinalities: Vec<f64> = registers.iter()
+        .map(|registers| match registers {
+            None => 0.0,
+            Some(registers) => {
+                let mut reg_histo = vec![0u32; params.histogram_len()];
+                for &reg in registers.iter() {
+                    reg_histo[reg as usize >> params.r()] += 1;
+                }
+                cardinality(&reg_histo, &params)
+            },
+        })
+        .collect();
+
+    let len = sketches.len();
+    let mut result = vec![0.0; len * len];
+    for i in 0..len {
+        result[i * len + i] = match metric {
+            Metric::Jaccard => 1.0,
+            Metric::Intersection => cardinalities[i],
+            Metric::Containment => if cardinalities[i] > 0.0 { 1.0 } else { 0.0 },
+        };
+
+        for j in (i + 1)..len {
+            let (similarity, union) = match (&registers[i], &registers[j]) {
+                (Some(a), Some(b)) => pairwise_similarity(a, b, cardinalities[i], cardinalities[j], &params),
+                _ => (0.0, cardinalities[i] + cardinalities[j]),
+            };
+            let intersection = similarity * union;
+            let ratio = |cardinality: f64| if cardinality > 0.0 { intersection / cardinality } else { 0.0 };
+
+            let (ij, ji) = match metric {
+                Metric::Jaccard => (similarity, similarity),
+                Metric::Intersection => (intersection, intersection),
+                Metric::Containment => (ratio(cardinalities[i]), ratio(cardinalities[j])),
+            };
+            result[i * len + j] = ij;
+            result[j * len + i] = ji;
         }
+    }
 
-        // do collision error correction only when estimating similarity of 2 sets.
-        // otherwise, expected collision computation in original paper (algorithm 2.1.5) does not works.
-        // see discussion in: https://github.com/LiveRamp/HyperMinHash-java/issues/13
-        if self.cardinalities.len() == 2 {
-            let ec = approx_expected_collision(self.cardinalities[0], self.cardinalities[1]);
-            (c as f64 - ec) / n as f64
-        } else {
-            c as f64 / n as f64
+    Ok(result)
+}
+
+/// Finds k sketches most similar to the query by given metric.
+/// Returns pairs of the index of candidate and its score, in descending order of score.
+/// For containment, the score is the fraction of the candidate contained in the query.
+///
+/// Registers and cardinality of the query are loaded only once, and only k best candidates are retained by a bounded heap.
+/// `None` candidates (e.g. non-existent keys) are skipped.
+pub fn top_k<T, H, U, G>(
+    query: &HyperMinHash<T, H>,
+    candidates: &[Option<&HyperMinHash<U, G>>],
+    k: usize,
+    metric: Metric) -> Result<Vec<(usize, f64)>, Error>
+    where T : RegisterVector, H : SketchHasher, U : RegisterVector, G : SketchHasher {
+
+    let params = query.params;
+    let registers: ArrayRegisters = (0..params.num_registers()).map(|i| query.registers.register_at(i)).collect();
+    let card_query = query.cardinality();
+    check_minhash(&query.hasher)?;
+
+    let mut heap = BinaryHeap::with_capacity(k + 1);
+    for (index, candidate) in candidates.iter().enumerate() {
+        let candidate = match candidate {
+            None => continue,
+            Some(candidate) => candidate,
+        };
+        if candidate.params != params {
+            return Err(Error::ParamsMismatch);
+        }
+        if candidate.hasher.id() != query.hasher.id() {
+            return Err(Error::HashMismatch);
+        }
+
+        let card_candidate = candidate.cardinality();
+        let (similarity, union) =
+            pairwise_similarity(&registers, &candidate.registers, card_query, card_candidate, &params);
+        let score = match metric {
+            Metric::Jaccard => similarity,
+            Metric::Intersection => similarity * union,
+            Metric::Containment if card_candidate > 0.0 => similarity * union / card_candidate,
+            Metric::Containment => 0.0,
+        };
+
+        // min-heap by score, so that the worst candidate is popped
+        heap.push(Reverse(Scored { score, index }));
+        if heap.len() > k {
+            heap.pop();
         }
     }
 
-    pub fn intersection(&self) -> f64 {
-        self.similarity() * self.union.cardinality()
+    Ok(heap.into_sorted_vec().into_iter().map(|Reverse(scored)| (scored.index, scored.score)).collect())
+}
+
+/// Cumulative intersection cardinality of each step of a funnel. i.e. |S1|, |S1∩S2|, |S1∩S2∩S3|, ...
+///
+/// Steps are combined incrementally into one MinHashCombiner, so each step costs as much as a single combine.
+/// Estimates are clamped to be non-increasing, since the intersection never grows by adding a step.
+/// `None` (e.g. non-existent keys) is regarded as an empty set, so the rest of steps are 0.
+pub fn funnel<T, H>(steps: &[Option<&HyperMinHash<T, H>>]) -> Result<Vec<f64>, Error>
+    where T : RegisterVector, H : SketchHasher {
+
+    let mut combiner = MinHashCombiner::new();
+    let mut result = Vec::with_capacity(steps.len());
+    let mut previous = f64::INFINITY;
+    for step in steps {
+        let step = match step {
+            None => break,
+            Some(step) => step,
+        };
+        combiner.combine(*step)?;
+        previous = combiner.intersection().clamp(0.0, previous);
+        result.push(previous);
+    }
+
+    result.resize(steps.len(), 0.0);
+    Ok(result)
+}
+
+/// Churn between the previous and the current period, where each sketch is the set of active ids in the period.
+///
+/// Intersection estimated by MinHashCombiner is clamped into [0, min(|A|, |B|)], so that new and lost are never negative.
+/// `None` (e.g. non-existent keys) is regarded as an empty set.
+pub fn churn<T, H, U, G>(previous: Option<&HyperMinHash<T, H>>, current: Option<&HyperMinHash<U, G>>) -> Result<Churn, Error>
+    where T : RegisterVector, H : SketchHasher, U : RegisterVector, G : SketchHasher {
+
+    let (card_previous, card_current, intersection) = match (previous, current) {
+        (Some(previous), Some(current)) => {
+            let mut combiner = MinHashCombiner::new();
+            combiner.combine(previous)?;
+            combiner.combine(current)?;
+            (combiner.cardinalities[0], combiner.cardinalities[1], combiner.intersection())
+        },
+        (Some(previous), None) => (previous.cardinality(), 0.0, 0.0),
+        (None, Some(current)) => (0.0, current.cardinality(), 0.0),
+        (None, None) => (0.0, 0.0, 0.0),
+    };
+
+    let retained = intersection.clamp(0.0, card_previous.min(card_current));
+    Ok(Churn {
+        retained,
+        new: card_current - retained,
+        lost: card_previous - retained,
+        retention: if card_previous > 0.0 { retained / card_previous } else { 0.0 },
+    })
+}
+
+/// Sketches without MinHash-part can't be compared, since their MinHash-parts are all zero.
+pub(crate) fn check_minhash<H : SketchHasher>(hasher: &H) -> Result<(), Error> {
+    if hasher.has_minhash() {
+        Ok(())
+    } else {
+        Err(Error::NoMinHash)
+    }
+}
+
+/// Candidate of top-k search. Higher score (and earlier index on tie) is greater.
+struct Scored {
+    score: f64,
+    index: usize,
+}
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score).then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+/// Similarity and union cardinality of two sketches. Same as MinHashCombiner for 2 sketches.
+pub(crate) fn pairwise_similarity<V : RegisterVector>(a: &[u32], b: &V, card_a: f64, card_b: f64, params: &Params) -> (f64, f64) {
+    let mut reg_histo = vec![0u32; params.histogram_len()];
+    let mut c = 0u64;
+    let mut n = 0u64;
+
+    for (i, &reg_a) in a.iter().enumerate() {
+        let reg_b = b.register_at(i);
+        let reg = reg_a.max(reg_b);
+        reg_histo[reg as usize >> params.r()] += 1;
+        if reg != 0 {
+            n += 1;
+            if reg_a == reg_b {
+                c += 1;
+            }
+        }
+    }
+
+    let union = cardinality(&reg_histo, params);
+    if c == 0 {
+        return (0.0, union);
+    }
+    let ec = approx_expected_collision(card_a, card_b, params);
+    ((c as f64 - ec) / n as f64, union)
+}
+
+/// Set-overlap metrics of multiple sketches.
+/// Dice and overlap coefficients are generalized to k sets as k|A∩B∩..| / (|A|+|B|+..) and |A∩B∩..| / min(|A|,|B|,..).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimates {
+    /// Cardinality of each sketch in combined order.
+    pub cardinalities: Vec<f64>,
+    pub union: f64,
+    pub similarity: f64,
+    pub intersection: f64,
+    /// Fraction of each sketch contained in all the others. i.e. |A∩B∩..| / |A|
+    pub containments: Vec<f64>,
+    pub dice: f64,
+    pub overlap: f64,
+}
+
+/// Churn between two periods. See `churn`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Churn {
+    /// |A∩B| where A is the previous period and B is the current period.
+    pub retained: f64,
+    /// |B\A|
+    pub new: f64,
+    /// |A\B|
+    pub lost: f64,
+    /// |A∩B| / |A|
+    pub retention: f64,
+}
+
+/// Estimate with its lower and upper bounds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfidenceInterval {
+    pub estimate: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+impl ConfidenceInterval {
+    /// Confidence interval of the cardinality estimated by given parameters.
+    /// Derived from relative standard error of HyperLogLog, 1.04 / sqrt(2^P).
+    pub fn cardinality(cardinality: f64, params: &Params, confidence: f64) -> Self {
+        let error = normal_quantile(confidence) * relative_standard_error(params) * cardinality;
+
+        Self {
+            estimate: cardinality,
+            lower: (cardinality - error).max(0.0),
+            upper: cardinality + error,
+        }
+    }
+
+    /// Confidence interval of the cardinality `fraction * union`,
+    /// where the fraction is estimated from n non-empty union registers.
+    pub(crate) fn fraction_of_union(fraction: f64, n: u64, union: f64, params: &Params, confidence: f64) -> Self {
+        if n == 0 {
+            return Self::exact(fraction * union);
+        }
+
+        let j = fraction.clamp(0.0, 1.0);
+        let var_j = j * (1.0 - j) / n as f64;
+        let rse = relative_standard_error(params);
+        let error = normal_quantile(confidence) * union * (var_j + j * j * rse * rse).sqrt();
+
+        Self {
+            estimate: fraction * union,
+            lower: (j * union - error).max(0.0),
+            upper: j * union + error,
+        }
+    }
+
+    pub(crate) fn exact(estimate: f64) -> Self {
+        Self { estimate, lower: estimate, upper: estimate, }
+    }
+}
+
+impl Default for MinHashCombiner {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-fn expected_collision(n: f64, m: f64, p: usize, q: usize, r: usize) -> f64 {
+/// Expected number of registers which collide by chance among sets of given cardinalities.
+/// Algorithm 2.1.5 in the original paper, generalized to k sets by taking the product of
+/// the probabilities that each set falls into the same (pattern length, MinHash-bits) bucket.
+fn expected_collision(cardinalities: &[f64], p: usize, hll_q: usize, r: usize) -> f64 {
     let _2r = 1 << r;
-    let _2q = 1 << q;
+    let _2q = hll_q;
 
     let mut x = 0.0;
     let mut b1: f64;
@@ -164,28 +641,101 @@ fn expected_collision(n: f64, m: f64, p: usize, q: usize, r: usize) -> f64 {
                 b2 = (j + 1) as f64 / den;
             }
 
-            let prx = (1.0 - b2).powf(n) - (1.0 - b1).powf(n);
-            let pry = (1.0 - b2).powf(m) - (1.0 - b1).powf(m);
-
-            x += prx * pry;
+            x += cardinalities.iter()
+                .map(|&n| (1.0 - b1).powf(n) - (1.0 - b2).powf(n))
+                .product::<f64>();
         }
     }
 
-    x * NUM_REGISTERS as f64
+    x * (1 << p) as f64
 }
 
-fn approx_expected_collision(n: f64, m: f64) -> f64 {
+fn approx_expected_collision(n: f64, m: f64, params: &Params) -> f64 {
     let (n, m) = if n < m { (m, n) } else { (n, m) };
+    let (p, hll_q, r) = (params.p(), params.hll_q(), params.r());
 
-    if n > 2f64.powi((HLL_Q + R) as i32) {
+    if n > 2f64.powi((hll_q + r) as i32) {
         // return 0 instead of panic if n is too large
         0.0
-    } else if n > 2f64.powi((P + 5) as i32) {
+    } else if n > 2f64.powi((p + 5) as i32) {
         let phi = (4.0 * n / m) / (1.0 + n / m).powi(2);
 
-        0.169919487159739093975315012348f64 * 2f64.powi((P - R) as i32) * phi
+        0.169_919_487_159_739_1_f64 * 2f64.powi(p as i32 - r as i32) * phi
     } else {
-        expected_collision(n, m, P, Q, 0) / 2f64.powi(R as i32)
+        expected_collision(&[n, m], p, hll_q, 0) / 2f64.powi(r as i32)
+    }
+}
+
+/// Approximation of expected collision among k sets, which is used for 3 or more sets.
+///
+/// Approximating (1 - b)^n by e^(-nb) in `expected_collision`, the sum over 2^R MinHash-bit buckets
+/// of each pattern length becomes a geometric series which can be computed in closed form.
+/// Hence the computation takes O(2^Q * k) instead of O(2^(Q+R) * k).
+/// Relative error of the approximation is O(n * 2^(-2P)), which is negligible for supported parameters.
+fn approx_expected_collision_k(cardinalities: &[f64], params: &Params) -> f64 {
+    if cardinalities.iter().any(|&n| n <= 0.0) {
+        return 0.0;
+    }
+
+    let (p, r) = (params.p(), params.r());
+    let hll_q = params.hll_q();
+    let _2r = 2f64.powi(r as i32);
+    let total: f64 = cardinalities.iter().sum();
+
+    let mut x = 0.0;
+    for i in 1..=hll_q {
+        // width of a MinHash-bit bucket and the offset of the first bucket in units of width
+        let (w, offset) = if i != hll_q {
+            (2f64.powi(-((p + r + i) as i32)), _2r)
+        } else {
+            (2f64.powi(-((p + r + i - 1) as i32)), 0.0)
+        };
+
+        // sum of e^(-total * (offset + j) * w) for j in 1..=2^R
+        let series = (-total * (offset + 1.0) * w).exp()
+            * ((-total * w * _2r).exp_m1() / (-total * w).exp_m1());
+        let prob: f64 = cardinalities.iter()
+            .map(|&n| -(-n * w).exp_m1())
+            .product();
+
+        x += series * prob;
+    }
+
+    x * 2f64.powi(p as i32)
+}
+
+fn relative_standard_error(params: &Params) -> f64 {
+    1.04 / (params.num_registers() as f64).sqrt()
+}
+
+/// Two-sided critical value of standard normal distribution for given confidence level.
+/// i.e. the (1 + confidence) / 2 quantile.
+/// Inverse CDF is computed by Peter J. Acklam's rational approximation. (relative error < 1.15e-9)
+fn normal_quantile(confidence: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1, 2.209_460_984_245_205e2, -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2, -3.066_479_806_614_716e1, 2.506_628_277_459_239];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1, 1.615_858_368_580_409e2, -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1, -1.328_068_155_288_572e1];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3, -3.223_964_580_411_365e-1, -2.400_758_277_161_838,
+        -2.549_732_539_343_734, 4.374_664_141_464_968, 2.938_163_982_698_783];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3, 3.224_671_290_700_398e-1, 2.445_134_137_142_996,
+        3.754_408_661_907_416];
+    const P_HIGH: f64 = 1.0 - 0.024_25;
+
+    let p = (1.0 + confidence) / 2.0;
+    if p <= P_HIGH {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q /
+            (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    } else {
+        let q = (-2.0 * (1.0 - p).ln()).sqrt();
+        -(((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5]) /
+            ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
     }
 }
 
@@ -197,12 +747,13 @@ struct PatLen {
 
 /// Use leftmost P bits to determine register.
 /// Find leftmost 1-bit position in next Q bits.
-fn pat_len(hash: &u128) -> PatLen {
-    let register = (hash >> (HASH_BITS - P) as u128) as usize;
+fn pat_len(hash: &u128, params: &Params) -> PatLen {
+    let p = params.p();
+    let register = (hash >> (HASH_BITS - p) as u128) as usize;
 
     let mut pat_len = 1u32;
-    for i in 1..=HLL_Q {
-        if hash & (1 << (HASH_BITS - P - i) as u128) != 0 {
+    for i in 1..=params.hll_q() {
+        if hash & (1 << (HASH_BITS - p - i) as u128) != 0 {
             break;
         }
         pat_len += 1;
@@ -211,11 +762,12 @@ fn pat_len(hash: &u128) -> PatLen {
     PatLen { register, len: pat_len, }
 }
 
-fn cardinality(reg_histo: &[u32]) -> f64 {
-    let m = NUM_REGISTERS as f64;
+fn cardinality(reg_histo: &[u32], params: &Params) -> f64 {
+    let m = params.num_registers() as f64;
+    let hll_q = params.hll_q();
 
-    let mut z = m * tau((m - reg_histo[HLL_Q + 1] as f64) / m);
-    for i in (1..=HLL_Q).rev() {
+    let mut z = m * tau((m - reg_histo[hll_q + 1] as f64) / m);
+    for i in (1..=hll_q).rev() {
         z += reg_histo[i] as f64;
         z *= 0.5;
     }
@@ -225,6 +777,78 @@ fn cardinality(reg_histo: &[u32]) -> f64 {
     (HLL_ALPHA_INF * m * m / z).round()
 }
 
+/// Maximum likelihood estimation. (Algorithm 8 in arXiv:1702.01284)
+fn ml_cardinality(reg_histo: &[u32], params: &Params) -> f64 {
+    let m = params.num_registers() as f64;
+    // relative error of the estimation to stop iteration
+    let epsilon = 1e-2 / m.sqrt();
+    let hll_q = params.hll_q();
+    let c = |k: usize| reg_histo[k] as f64;
+
+    if c(hll_q + 1) == m {
+        return f64::INFINITY;
+    }
+    if c(0) == m {
+        return 0.0;
+    }
+
+    let k_min = (0..=hll_q + 1).find(|&k| reg_histo[k] > 0).unwrap_or(0).max(1);
+    let k_max = (0..=hll_q + 1).rev().find(|&k| reg_histo[k] > 0).unwrap_or(0).min(hll_q);
+
+    let mut z = 0.0;
+    for k in (k_min..=k_max).rev() {
+        z = 0.5 * z + c(k);
+    }
+    z *= 2f64.powi(-(k_min as i32));
+
+    let mut c_prime = c(hll_q + 1);
+    if hll_q >= 1 {
+        c_prime += c(k_max);
+    }
+
+    let mut g_prev = 0.0;
+    let a = z + c(0);
+    let b = z + c(hll_q + 1) * 2f64.powi(-(hll_q as i32));
+    let m_prime = m - c(0);
+
+    let mut x = if b <= 1.5 * a {
+        m_prime / (0.5 * b + a)
+    } else {
+        m_prime / b * (1.0 + b / a).ln()
+    };
+
+    // secant method
+    let mut dx = x;
+    while dx > x * epsilon {
+        let kappa = 2 + (x.log2().floor().max(0.0) as usize);
+        let mut x_prime = x * 2f64.powi(-(k_max.max(kappa) as i32) - 1);
+        let x_prime2 = x_prime * x_prime;
+        let mut h = x_prime - x_prime2 / 3.0 + (x_prime2 * x_prime2) * (1.0 / 45.0 - x_prime2 / 472.5);
+        for _ in (k_max..kappa).rev() {
+            h = (x_prime + h * (1.0 - h)) / (x_prime + (1.0 - h));
+            x_prime *= 2.0;
+        }
+
+        let mut g = c_prime * h;
+        for k in (k_min..k_max).rev() {
+            h = (x_prime + h * (1.0 - h)) / (x_prime + (1.0 - h));
+            g += c(k) * h;
+            x_prime *= 2.0;
+        }
+        g += x * a;
+
+        if g > g_prev && m_prime >= g {
+            dx *= (m_prime - g) / (g - g_prev);
+        } else {
+            dx = 0.0;
+        }
+        x += dx;
+        g_prev = g;
+    }
+
+    (m * x).round()
+}
+
 fn tau(mut x: f64) -> f64 {
     if x == 0.0 || x == 1.0 {
         return 0.0;
@@ -250,7 +874,7 @@ fn tau(mut x: f64) -> f64 {
 
 fn sigma(mut x: f64) -> f64 {
     if x == 1.0 {
-        return std::f64::INFINITY;
+        return f64::INFINITY;
     }
 
     let mut z_prime;
@@ -273,34 +897,107 @@ fn sigma(mut x: f64) -> f64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hyperminhash::hash::{HashAlgorithm, Xxh3};
+
+    #[test]
+    fn test_register_overflow() {
+        // pattern length 2^Q + 1 shifted by R doesn't fit in 16 bits without capping
+        for &(q, r) in [(2, 14), (3, 13), (4, 12), (6, 10)].iter() {
+            let params = Params::new(8, q, r).unwrap();
+            let mut sketch = HyperMinHash::with_params(new_array_registers(&params), params);
+            for i in 0..100000 {
+                sketch.add(format!("id{}", i).as_bytes());
+            }
+            assert!(sketch.registers.iter().all(|&reg| reg <= u32::from(u16::MAX)));
+            if q < 6 {
+                // capped pattern length is reached
+                assert!(sketch.registers.iter().any(|&reg| (reg >> r) as usize == params.hll_q() + 1));
+            }
+
+            // registers decode back from both sparse and dense representation
+            let decoded = HyperMinHash::from_bytes(&sketch.to_bytes()).unwrap();
+            assert_eq!(decoded.registers, sketch.registers);
+            let mut sparse = HyperMinHash::with_params(new_array_registers(&params), params);
+            sparse.add(b"id0");
+            assert_eq!(HyperMinHash::from_bytes(&sparse.to_bytes()).unwrap().registers, sparse.registers);
+        }
+    }
+
+    #[test]
+    fn test_merge_cardinality() {
+        let mut sketch1 = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        let mut sketch2 = HyperMinHash::with_hasher(new_array_registers(&Params::default()), Params::default(), Xxh3);
+        for i in 0..10000 {
+            sketch1.add(format!("a{}", i).as_bytes());
+            sketch2.add(format!("b{}", i).as_bytes());
+        }
+        assert_eq!(sketch1.merge(&sketch2), Err(Error::HashMismatch));
+
+        // disjoint sets
+        sketch1.merge_cardinality(&sketch2).unwrap();
+        assert!((sketch1.cardinality() - 20000.0).abs() < 20000.0 * 0.02);
+        assert!(sketch1.registers.iter().all(|&reg| reg & ((1 << R) - 1) == 0));
+
+        let params = Params::new(12, 6, 10).unwrap();
+        let other = HyperMinHash::with_params(new_array_registers(&params), params);
+        assert_eq!(sketch1.merge_cardinality(&other), Err(Error::ParamsMismatch));
+    }
 
     #[test]
     fn test_wrap() {
-        let sketch: HyperMinHash<ArrayRegisters> = HyperMinHash::wrap(new_array_registers());
+        let sketch: HyperMinHash<ArrayRegisters> = HyperMinHash::wrap(new_array_registers(&Params::default()));
 
-        assert_eq!(sketch.registers.len(), NUM_REGISTERS);
+        assert_eq!(sketch.registers.len(), 1 << P);
     }
 
     #[test]
     fn test_pat_len() {
-        assert_eq!(pat_len(&0u128),
-                   PatLen { register: 0, len: 65, });
+        let params = Params::default();
+
+        // capped to fit in 6 bits
+        assert_eq!(pat_len(&0u128, &params),
+                   PatLen { register: 0, len: 63, });
 
-        assert_eq!(pat_len(&0x1_00000000_00000000u128),
+        assert_eq!(pat_len(&0x1_00000000_00000000u128, &params),
                    PatLen { register: 0, len: 50, });
+
+        let params = Params::new(10, 6, 10).unwrap();
+
+        assert_eq!(pat_len(&0xffc00000_00000000_00000000_00000000u128, &params),
+                   PatLen { register: 1023, len: 63, });
+
+        let params = Params::new(10, 4, 8).unwrap();
+
+        assert_eq!(pat_len(&0xffc00000_00000000_00000000_00000000u128, &params),
+                   PatLen { register: 1023, len: 17, });
     }
 
     #[test]
     fn test_add() {
-        let mut sketch: HyperMinHash<ArrayRegisters> = HyperMinHash::wrap(new_array_registers());
+        let mut sketch: HyperMinHash<ArrayRegisters> = HyperMinHash::wrap(new_array_registers(&Params::default()));
 
         assert!(sketch.add("a".as_bytes()));
         assert!(!sketch.add("a".as_bytes()));
     }
 
+    #[test]
+    fn test_add_hash() {
+        let params = Params::default();
+        let mut sketch = HyperMinHash::wrap(new_array_registers(&params));
+        let mut hashed = HyperMinHash::wrap(new_array_registers(&params));
+        for i in 0..10000 {
+            let element = format!("id{}", i);
+            sketch.add(element.as_bytes());
+            hashed.add_hash(Murmur3.hash(element.as_bytes()));
+        }
+
+        assert_eq!(sketch.registers, hashed.registers);
+        assert!(!hashed.add_hash(Murmur3.hash(b"id0")));
+    }
+
     #[test]
     fn test_cardinality() {
-        let mut sketch = HyperMinHash::wrap(new_array_registers());
+        let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
 
         for i in 0..10 {
             sketch.add(format!("id{}", i).as_bytes());
@@ -313,14 +1010,363 @@ mod tests {
         assert_eq!(sketch.cardinality() as u64, 997689);
     }
 
+    #[test]
+    fn test_ml_cardinality() {
+        let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        assert_eq!(sketch.cardinality_with(Estimator::MaximumLikelihood), 0.0);
+
+        let mut n = 0;
+        for &(until, tolerance) in [(10, 0.0), (1_000_000, 0.02)].iter() {
+            while n < until {
+                sketch.add(format!("id{}", n).as_bytes());
+                n += 1;
+            }
+
+            let improved = sketch.cardinality_with(Estimator::Improved);
+            let ml = sketch.cardinality_with(Estimator::MaximumLikelihood);
+            assert!((improved - n as f64).abs() <= n as f64 * tolerance);
+            assert!((ml - n as f64).abs() <= n as f64 * tolerance);
+            // both estimators are almost equivalent in accuracy
+            assert!((ml - improved).abs() <= n as f64 * 0.005);
+        }
+    }
+
+    #[test]
+    fn test_normal_quantile() {
+        assert!((normal_quantile(0.0)).abs() < 1e-9);
+        assert!((normal_quantile(0.6827) - 1.0).abs() < 1e-3);
+        assert!((normal_quantile(0.95) - 1.959_964).abs() < 1e-6);
+        assert!((normal_quantile(0.99) - 2.575_829).abs() < 1e-6);
+        assert!((normal_quantile(0.999) - 3.290_527).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_confidence_interval() {
+        let params = Params::default();
+        let interval = ConfidenceInterval::cardinality(10000.0, &params, 0.95);
+        // RSE for P=14 is 0.8125%
+        assert!((interval.upper - 10159.25).abs() < 0.1);
+        assert!((interval.lower - 9840.75).abs() < 0.1);
+
+        let mut sketch_1 = HyperMinHash::wrap(new_array_registers(&params));
+        let mut sketch_2 = HyperMinHash::wrap(new_array_registers(&params));
+        for i in 0..10000 {
+            sketch_1.add(format!("a_{}", i).as_bytes());
+            sketch_2.add(format!("b_{}", i).as_bytes());
+        }
+        for i in 0..1000 {
+            sketch_1.add(format!("ab_{}", i).as_bytes());
+            sketch_2.add(format!("ab_{}", i).as_bytes());
+        }
+        let mut combiner = MinHashCombiner::new();
+        combiner.combine(&sketch_1).unwrap();
+        combiner.combine(&sketch_2).unwrap();
+
+        let similarity = combiner.similarity_interval(0.95);
+        assert_eq!(similarity.estimate, combiner.similarity());
+        assert!(similarity.lower < 1000.0 / 21000.0 && 1000.0 / 21000.0 < similarity.upper);
+
+        let intersection = combiner.intersection_interval(0.95);
+        assert_eq!(intersection.estimate, combiner.intersection());
+        assert!(intersection.lower < 1000.0 && 1000.0 < intersection.upper);
+        // wider interval for higher confidence
+        let wider = combiner.intersection_interval(0.99);
+        assert!(wider.lower < intersection.lower && intersection.upper < wider.upper);
+
+        let empty = MinHashCombiner::new().intersection_interval(0.95);
+        assert_eq!((empty.lower, empty.upper), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_estimates() {
+        let mut sketch_1 = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        let mut sketch_2 = HyperMinHash::wrap(new_array_registers(&Params::default()));
+
+        for i in 0..10000 {
+            sketch_1.add(format!("a_{}", i).as_bytes());
+        }
+        for i in 0..30000 {
+            sketch_2.add(format!("b_{}", i).as_bytes());
+        }
+        for i in 0..10000 {
+            sketch_1.add(format!("ab_{}", i).as_bytes());
+            sketch_2.add(format!("ab_{}", i).as_bytes());
+        }
+        let mut combiner = MinHashCombiner::new();
+        combiner.combine(&sketch_1).unwrap();
+        combiner.combine(&sketch_2).unwrap();
+
+        let estimates = combiner.estimates();
+        assert_eq!(estimates.cardinalities, vec![sketch_1.cardinality(), sketch_2.cardinality()]);
+        assert_eq!(estimates.similarity, combiner.similarity());
+        assert_eq!(estimates.intersection, combiner.intersection());
+        assert!((estimates.union - 50000.0).abs() < 50000.0 * 0.02);
+
+        let close = |actual: f64, expected: f64| (actual - expected).abs() < expected * 0.1;
+        assert!(close(estimates.containments[0], 0.5));
+        assert!(close(estimates.containments[1], 0.25));
+        assert!(close(estimates.dice, 2.0 * 10000.0 / 60000.0));
+        assert!(close(estimates.overlap, 0.5));
+
+        let empty = MinHashCombiner::new().estimates();
+        assert!(empty.cardinalities.is_empty() && empty.containments.is_empty());
+        assert_eq!((empty.union, empty.intersection, empty.dice, empty.overlap), (0.0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_pairwise_matrix() {
+        let mut sketches = vec![];
+        for offset in [0, 10000, 20000].iter() {
+            let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+            for i in *offset..(offset + 30000) {
+                sketch.add(format!("id{}", i).as_bytes());
+            }
+            sketches.push(sketch);
+        }
+        let mut operands: Vec<_> = sketches.iter().map(Some).collect();
+        operands.push(None);
+
+        let jaccard = pairwise_matrix(&operands, Metric::Jaccard).unwrap();
+        let intersection = pairwise_matrix(&operands, Metric::Intersection).unwrap();
+        let containment = pairwise_matrix(&operands, Metric::Containment).unwrap();
+        assert_eq!(jaccard.len(), 16);
+
+        for i in 0..3 {
+            for j in 0..3 {
+                let mut combiner = MinHashCombiner::new();
+                combiner.combine(&sketches[i]).unwrap();
+                combiner.combine(&sketches[j]).unwrap();
+
+                if i != j {
+                    assert_eq!(jaccard[i * 4 + j], combiner.similarity());
+                    assert_eq!(intersection[i * 4 + j], combiner.intersection());
+                    assert_eq!(containment[i * 4 + j], combiner.intersection() / sketches[i].cardinality());
+                }
+            }
+            assert_eq!(jaccard[i * 4 + i], 1.0);
+            assert_eq!(intersection[i * 4 + i], sketches[i].cardinality());
+            assert_eq!(containment[i * 4 + i], 1.0);
+
+            // empty set
+            assert_eq!((jaccard[i * 4 + 3], jaccard[3 * 4 + i]), (0.0, 0.0));
+            assert_eq!((containment[i * 4 + 3], containment[3 * 4 + i]), (0.0, 0.0));
+        }
+        assert_eq!(containment[15], 0.0);
+
+        let small = Params::new(10, 6, 10).unwrap();
+        let other = HyperMinHash::with_params(new_array_registers(&small), small);
+        assert_eq!(pairwise_matrix(&[Some(&sketches[0]), Some(&other)], Metric::Jaccard), Err(Error::ParamsMismatch));
+    }
+
+    #[test]
+    fn test_top_k() {
+        let mut query = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in 0..10000 {
+            query.add(format!("id{}", i).as_bytes());
+        }
+
+        // i-th candidate shares (i * 1000) elements with the query, out of 5000 elements
+        let mut candidates = vec![];
+        for i in 0..6 {
+            let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+            for j in 0..(i * 1000) {
+                sketch.add(format!("id{}", j).as_bytes());
+            }
+            for j in (i * 1000)..5000 {
+                sketch.add(format!("other{}_{}", i, j).as_bytes());
+            }
+            candidates.push(sketch);
+        }
+        let mut operands: Vec<_> = candidates.iter().map(Some).collect();
+        operands.insert(2, None);
+
+        let result = top_k(&query, &operands, 3, Metric::Jaccard).unwrap();
+        assert_eq!(result.iter().map(|&(index, _)| index).collect::<Vec<_>>(), vec![6, 5, 4]);
+        for &(index, score) in result.iter() {
+            let mut combiner = MinHashCombiner::new();
+            combiner.combine(&query).unwrap();
+            combiner.combine(operands[index].unwrap()).unwrap();
+            assert_eq!(score, combiner.similarity());
+        }
+
+        let result = top_k(&query, &operands, 10, Metric::Intersection).unwrap();
+        assert_eq!(result.len(), 6);
+        assert!(result.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert!((result[0].1 - 5000.0).abs() < 5000.0 * 0.05);
+
+        let result = top_k(&query, &operands, 1, Metric::Containment).unwrap();
+        assert_eq!(result[0].0, 6);
+        assert!((result[0].1 - 1.0).abs() < 0.05);
+
+        assert!(top_k(&query, &operands, 0, Metric::Jaccard).unwrap().is_empty());
+
+        let small = Params::new(10, 6, 10).unwrap();
+        let other = HyperMinHash::with_params(new_array_registers(&small), small);
+        assert_eq!(top_k(&query, &[Some(&other)], 1, Metric::Jaccard), Err(Error::ParamsMismatch));
+    }
+
+    #[test]
+    fn test_funnel() {
+        // i-th step contains ids of [0, 10000 - i * 2000)
+        let steps: Vec<_> = (0..4).map(|i| {
+            let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+            for j in 0..(10000 - i * 2000) {
+                sketch.add(format!("id{}", j).as_bytes());
+            }
+            sketch
+        }).collect();
+        let mut operands: Vec<_> = steps.iter().map(Some).collect();
+
+        let result = funnel(&operands).unwrap();
+        assert_eq!(result.len(), 4);
+        assert_eq!(result[0], steps[0].cardinality());
+        for (i, &value) in result.iter().enumerate() {
+            let expected = (10000 - i * 2000) as f64;
+            assert!((value - expected).abs() < expected * 0.05);
+        }
+        assert!(result.windows(2).all(|pair| pair[0] >= pair[1]));
+
+        let mut combiner = MinHashCombiner::new();
+        combiner.combine(&steps[0]).unwrap();
+        combiner.combine(&steps[1]).unwrap();
+        assert_eq!(result[1], combiner.intersection());
+
+        operands.insert(2, None);
+        let result = funnel(&operands).unwrap();
+        assert_eq!(result.len(), 5);
+        assert_eq!(&result[2..], &[0.0, 0.0, 0.0]);
+        assert!(funnel::<ArrayRegisters, Murmur3>(&[]).unwrap().is_empty());
+
+        let small = Params::new(10, 6, 10).unwrap();
+        let other = HyperMinHash::with_params(new_array_registers(&small), small);
+        assert_eq!(funnel(&[Some(&steps[0]), Some(&other)]), Err(Error::ParamsMismatch));
+    }
+
+    #[test]
+    fn test_churn() {
+        let mut previous = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in 0..10000 {
+            previous.add(format!("id{}", i).as_bytes());
+        }
+        let mut current = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in 6000..20000 {
+            current.add(format!("id{}", i).as_bytes());
+        }
+
+        let result = churn(Some(&previous), Some(&current)).unwrap();
+        assert!((result.retained - 4000.0).abs() < 4000.0 * 0.05);
+        assert!((result.new - 10000.0).abs() < 10000.0 * 0.05);
+        assert!((result.lost - 6000.0).abs() < 6000.0 * 0.05);
+        assert!((result.retention - 0.4).abs() < 0.02);
+        assert_eq!(result.retained + result.lost, previous.cardinality());
+        assert_eq!(result.retained + result.new, current.cardinality());
+
+        // identical sets may be estimated larger than either, but nothing is new or lost
+        let result = churn(Some(&previous), Some(&previous)).unwrap();
+        assert!(result.new >= 0.0 && result.lost >= 0.0);
+        assert!(result.retention <= 1.0);
+
+        let result = churn(Some(&previous), None::<&HyperMinHash<ArrayRegisters>>).unwrap();
+        assert_eq!(result, Churn { retained: 0.0, new: 0.0, lost: previous.cardinality(), retention: 0.0 });
+        let result = churn(None::<&HyperMinHash<ArrayRegisters>>, Some(&current)).unwrap();
+        assert_eq!(result, Churn { retained: 0.0, new: current.cardinality(), lost: 0.0, retention: 0.0 });
+
+        let small = Params::new(10, 6, 10).unwrap();
+        let other = HyperMinHash::with_params(new_array_registers(&small), small);
+        assert_eq!(churn(Some(&previous), Some(&other)), Err(Error::ParamsMismatch));
+    }
+
+    #[test]
+    fn test_intersection_registers() {
+        let params = Params::new(10, 6, 10).unwrap();
+        let mut a = HyperMinHash::with_params(new_array_registers(&params), params);
+        let mut b = HyperMinHash::with_params(new_array_registers(&params), params);
+        for i in 0..10000 {
+            a.add(format!("id{}", i).as_bytes());
+            b.add(format!("id{}", i + 5000).as_bytes());
+        }
+
+        let mut combiner = MinHashCombiner::new();
+        combiner.combine(&a).unwrap();
+        combiner.combine(&b).unwrap();
+        assert_eq!(combiner.params(), params);
+
+        let registers = combiner.intersection_registers();
+        let mut retained = 0;
+        for (i, &reg) in registers.iter().enumerate() {
+            if reg != 0 {
+                assert_eq!(reg, a.registers[i]);
+                assert_eq!(reg, b.registers[i]);
+                retained += 1;
+            }
+        }
+        // about a third of registers are shared by both
+        assert!(retained > params.num_registers() / 4 && retained < params.num_registers() / 2);
+    }
+
+    #[test]
+    fn test_no_minhash() {
+        let params = Params::default();
+        let mut hll = HyperMinHash::with_hasher(new_array_registers(&params), params, HashAlgorithm::RedisHll);
+        hll.add(b"id");
+        let mut other = HyperMinHash::with_hasher(new_array_registers(&params), params, HashAlgorithm::RedisHll);
+        other.merge(&hll).unwrap();
+
+        assert_eq!(MinHashCombiner::new().combine(&hll), Err(Error::NoMinHash));
+        assert_eq!(pairwise_matrix(&[Some(&hll), Some(&other)], Metric::Jaccard), Err(Error::NoMinHash));
+        assert_eq!(top_k(&hll, &[Some(&other)], 1, Metric::Jaccard), Err(Error::NoMinHash));
+    }
+
+    #[test]
+    fn test_custom_params() {
+        let params = Params::new(10, 4, 12).unwrap();
+        let mut sketch = HyperMinHash::with_params(new_array_registers(&params), params);
+
+        for i in 0..10000 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        assert_eq!(sketch.registers.len(), 1024);
+        assert!((sketch.cardinality() - 10000.0).abs() < 10000.0 * 0.1);
+
+        let mut other = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        assert_eq!(other.merge(&sketch), Err(Error::ParamsMismatch));
+
+        let mut combiner = MinHashCombiner::new();
+        combiner.combine(&sketch).unwrap();
+        assert_eq!(combiner.combine(&other), Err(Error::ParamsMismatch));
+    }
+
+    #[test]
+    fn test_hasher() {
+        let params = Params::default();
+        let mut murmur3 = HyperMinHash::wrap(new_array_registers(&params));
+        let mut xxh3 = HyperMinHash::with_hasher(new_array_registers(&params), params, Xxh3);
+        for i in 0..10000 {
+            murmur3.add(format!("id{}", i).as_bytes());
+            xxh3.add(format!("id{}", i).as_bytes());
+        }
+        assert_ne!(murmur3.registers, xxh3.registers);
+        assert!((xxh3.cardinality() - 10000.0).abs() < 10000.0 * 0.05);
+
+        assert_eq!(murmur3.merge(&xxh3), Err(Error::HashMismatch));
+
+        let mut dynamic = HyperMinHash::with_hasher(new_array_registers(&params), params, HashAlgorithm::Xxh3);
+        dynamic.merge(&xxh3).unwrap();
+        assert_eq!(dynamic.registers, xxh3.registers);
+
+        let mut combiner = MinHashCombiner::new();
+        combiner.combine(&xxh3).unwrap();
+        assert_eq!(combiner.combine(&murmur3), Err(Error::HashMismatch));
+    }
+
     #[test]
     fn test_intersection_10000() {
-        let mut sketch_1 = HyperMinHash::wrap(new_array_registers());
+        let mut sketch_1 = HyperMinHash::wrap(new_array_registers(&Params::default()));
         for i in 0..10000 {
             sketch_1.add(format!("a_{}", i).as_bytes());
         }
 
-        let mut sketch_2 = HyperMinHash::wrap(new_array_registers());
+        let mut sketch_2 = HyperMinHash::wrap(new_array_registers(&Params::default()));
         for i in 0..10000 {
             sketch_2.add(format!("b_{}", i).as_bytes());
         }
@@ -331,20 +1377,62 @@ mod tests {
         }
 
         let mut combiner = MinHashCombiner::new();
-        combiner.combine(&sketch_1);
-        combiner.combine(&sketch_2);
+        combiner.combine(&sketch_1).unwrap();
+        combiner.combine(&sketch_2).unwrap();
 
         assert_eq!(combiner.intersection() as u64, 107);
     }
 
+    #[test]
+    fn test_expected_collision_k() {
+        let params = Params::new(12, 6, 10).unwrap();
+        let (p, hll_q, r) = (params.p(), params.hll_q(), params.r());
+
+        for &n in [1000.0, 10000.0, 100000.0, 1_000_000.0].iter() {
+            for cardinalities in [vec![n, n], vec![n, n / 2.0, n * 2.0], vec![n; 5]].iter() {
+                let exact = expected_collision(cardinalities, p, hll_q, r);
+                let approx = approx_expected_collision_k(cardinalities, &params);
+                assert!((approx - exact).abs() <= exact * 0.01);
+            }
+        }
+
+        assert_eq!(approx_expected_collision_k(&[1000.0, 0.0, 1000.0], &params), 0.0);
+    }
+
+    #[test]
+    fn test_intersection_3_way() {
+        // small R to make collisions frequent
+        let params = Params::new(14, 6, 4).unwrap();
+        for &(n, shared, expected) in [(10000, 100, 108), (100_000, 1000, 1009)].iter() {
+            let mut combiner = MinHashCombiner::new();
+            for s in 0..3 {
+                let mut sketch = HyperMinHash::with_params(new_array_registers(&params), params);
+                for i in 0..n {
+                    sketch.add(format!("{}_{}", s, i).as_bytes());
+                }
+                for i in 0..shared {
+                    sketch.add(format!("abc_{}", i).as_bytes());
+                }
+                combiner.combine(&sketch).unwrap();
+            }
+            assert_eq!(combiner.intersection() as u64, expected);
+
+            // without collision correction
+            let c = combiner.reg_intersection.iter().filter(|&&r| r != 0).count() as f64;
+            let u = combiner.union.registers.iter().filter(|&&r| r != 0).count() as f64;
+            let raw = c / u * combiner.union.cardinality();
+            assert!(raw - shared as f64 > combiner.intersection() - shared as f64);
+        }
+    }
+
     #[test]
     fn test_intersection_1_000_000() {
-        let mut sketch_1 = HyperMinHash::wrap(new_array_registers());
+        let mut sketch_1 = HyperMinHash::wrap(new_array_registers(&Params::default()));
         for i in 0..1_000_000 {
             sketch_1.add(format!("a_{}", i).as_bytes());
         }
 
-        let mut sketch_2 = HyperMinHash::wrap(new_array_registers());
+        let mut sketch_2 = HyperMinHash::wrap(new_array_registers(&Params::default()));
         for i in 0..1_000_000 {
             sketch_2.add(format!("b_{}", i).as_bytes());
         }
@@ -355,8 +1443,8 @@ mod tests {
         }
 
         let mut combiner = MinHashCombiner::new();
-        combiner.combine(&sketch_1);
-        combiner.combine(&sketch_2);
+        combiner.combine(&sketch_1).unwrap();
+        combiner.combine(&sketch_2).unwrap();
 
         assert_eq!(combiner.intersection() as u64, 9182);
     }
@@ -0,0 +1,165 @@
+use super::{Params, RegisterVector};
+use super::dma::CByteArray;
+use std::mem::size_of;
+
+/// RegisterVector impl which stores only non-empty registers.
+/// Registers are kept as (register index, register value) pairs sorted by index.
+/// Each pair is stored as two 16-bit integers in little endian.
+pub struct SparseVector {
+    entries: Vec<(u16, u16)>,
+}
+
+impl SparseVector {
+    pub const SINGLE_ENTRY_BYTES: usize = 2 * size_of::<u16>();
+
+    pub fn sparse_bytes(num_entries: usize) -> usize {
+        num_entries * SparseVector::SINGLE_ENTRY_BYTES
+    }
+
+    /// Collect non-empty registers.
+    pub fn from_registers<T : RegisterVector>(params: &Params, registers: &T) -> Self {
+        let entries = (0..params.num_registers())
+            .map(|i| (i as u16, registers.register_at(i) as u16))
+            .filter(|&(_, value)| value != 0)
+            .collect();
+
+        Self { entries, }
+    }
+
+    /// Check that given bytes are valid sparse registers.
+    /// i.e. indices are strictly increasing and in range, and values are non-zero.
+    pub fn validate(data: &CByteArray, params: &Params) -> bool {
+        if data.len() % SparseVector::SINGLE_ENTRY_BYTES != 0 {
+            return false;
+        }
+
+        let mut prev: Option<u16> = None;
+        for (idx, value) in Self::iter_entries(data) {
+            if idx as usize >= params.num_registers() || value == 0 {
+                return false;
+            }
+            if prev.is_some_and(|prev| prev >= idx) {
+                return false;
+            }
+            prev = Some(idx);
+        }
+
+        true
+    }
+
+    /// Read registers from given bytes.
+    /// Bytes are expected to be validated beforehand.
+    pub fn read(data: &CByteArray) -> Self {
+        Self { entries: Self::iter_entries(data).collect(), }
+    }
+
+    pub fn write(&self, data: &mut CByteArray) {
+        for (i, &(idx, value)) in self.entries.iter().enumerate() {
+            let offset = i * SparseVector::SINGLE_ENTRY_BYTES;
+
+            data[offset    ] = ( idx       & 0xff) as u8;
+            data[offset + 1] = ((idx >> 8) & 0xff) as u8;
+            data[offset + 2] = ( value       & 0xff) as u8;
+            data[offset + 3] = ((value >> 8) & 0xff) as u8;
+        }
+    }
+
+    /// Number of non-empty registers.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn iter_entries(data: &CByteArray) -> impl Iterator<Item = (u16, u16)> + '_ {
+        (0..data.len() / SparseVector::SINGLE_ENTRY_BYTES).map(move |i| {
+            let offset = i * SparseVector::SINGLE_ENTRY_BYTES;
+
+            let idx = u16::from(data[offset]) | u16::from(data[offset + 1]) << 8;
+            let value = u16::from(data[offset + 2]) | u16::from(data[offset + 3]) << 8;
+
+            (idx, value)
+        })
+    }
+}
+
+impl RegisterVector for SparseVector {
+    fn register_at(&self, idx: usize) -> u32 {
+        match self.entries.binary_search_by_key(&(idx as u16), |&(i, _)| i) {
+            Ok(pos) => u32::from(self.entries[pos].1),
+            Err(_) => 0,
+        }
+    }
+
+    fn set_register(&mut self, idx: usize, value: u32) {
+        match self.entries.binary_search_by_key(&(idx as u16), |&(i, _)| i) {
+            Ok(pos) if value == 0 => { self.entries.remove(pos); },
+            Ok(pos) => self.entries[pos].1 = value as u16,
+            Err(_) if value == 0 => {},
+            Err(pos) => self.entries.insert(pos, (idx as u16, value as u16)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_register() {
+        let mut sparse = SparseVector { entries: Vec::new(), };
+        sparse.set_register(100, 3);
+        sparse.set_register(5, 7);
+        sparse.set_register(100, 4);
+
+        assert_eq!(sparse.len(), 2);
+        assert_eq!(sparse.register_at(5), 7);
+        assert_eq!(sparse.register_at(6), 0);
+        assert_eq!(sparse.register_at(100), 4);
+
+        sparse.set_register(5, 0);
+        assert_eq!(sparse.len(), 1);
+        assert_eq!(sparse.register_at(5), 0);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let params = Params::default();
+        let mut sparse = SparseVector { entries: Vec::new(), };
+        sparse.set_register(1, 0x1234);
+        sparse.set_register(16383, 0xffff);
+
+        let mut arr = [0u8; 8];
+        let mut data = CByteArray::wrap(arr.as_mut_ptr(), arr.len());
+        sparse.write(&mut data);
+
+        assert_eq!(arr, [0x01, 0x00, 0x34, 0x12, 0xff, 0x3f, 0xff, 0xff]);
+
+        let data = CByteArray::wrap(arr.as_mut_ptr(), arr.len());
+        assert!(SparseVector::validate(&data, &params));
+
+        let read = SparseVector::read(&data);
+        assert_eq!(read.len(), 2);
+        assert_eq!(read.register_at(1), 0x1234);
+        assert_eq!(read.register_at(16383), 0xffff);
+    }
+
+    #[test]
+    fn test_validate() {
+        let params = Params::default();
+
+        // not sorted
+        let mut arr = [0x02u8, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00];
+        assert!(!SparseVector::validate(&CByteArray::wrap(arr.as_mut_ptr(), arr.len()), &params));
+
+        // empty register
+        let mut arr = [0x01u8, 0x00, 0x00, 0x00];
+        assert!(!SparseVector::validate(&CByteArray::wrap(arr.as_mut_ptr(), arr.len()), &params));
+
+        // out of range
+        let mut arr = [0x00u8, 0x40, 0x01, 0x00];
+        assert!(!SparseVector::validate(&CByteArray::wrap(arr.as_mut_ptr(), arr.len()), &params));
+
+        // partial entry
+        let mut arr = [0x00u8, 0x00, 0x01];
+        assert!(!SparseVector::validate(&CByteArray::wrap(arr.as_mut_ptr(), arr.len()), &params));
+    }
+}
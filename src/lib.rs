@@ -1,4 +1,9 @@
 //! A HyperMinHash (arXiv:1710.08436) implementation for Redis
+//!
+//! Besides the Redis module, sketches can be built and serialized in plain Rust.
+//! Bytes produced by `HyperMinHash::to_bytes` are compatible with what the module stores.
+//! The Redis module itself is built only with `redis-module` feature. (enabled by default)
 
-mod hyperminhash;
+pub mod hyperminhash;
+#[cfg(feature = "redis-module")]
 mod redis;
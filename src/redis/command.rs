@@ -1,13 +1,82 @@
 //! Redis commands implementation.
 
 use super::*;
-use crate::hyperminhash::sketch::{HyperMinHash, MinHashCombiner};
-use crate::hyperminhash::new_array_registers;
-use dma::CByteArray;
-use repr::{HyperMinHashRepr, Registers};
-use libc::{c_double, c_int, size_t, c_longlong};
+use crate::hyperminhash::dma::CByteArray;
+use crate::hyperminhash::expr::{Expr, ParseError};
+use crate::hyperminhash::lsh::{cluster, LshError, LshIndex};
+use crate::hyperminhash::repr::{HyperMinHashRepr, Registers};
+use crate::hyperminhash::sketch::{churn, funnel, pairwise_matrix, top_k, ConfidenceInterval, Estimates, HyperMinHash, MinHashCombiner};
+use crate::hyperminhash::sparse::SparseVector;
+use crate::hyperminhash::hash::{HashAlgorithm, SketchHasher};
+use crate::hyperminhash::hll::is_hll;
+use crate::hyperminhash::shingle::{Normalization, Shingle, Shingler};
+use crate::hyperminhash::{new_array_registers, ArrayRegisters, Error, Estimator, Metric, Params, RegisterVector};
+use datatype::{SketchValue, HYPERMINHASH_TYPE};
+use lsh::LSH_INDEX_TYPE;
+use libc::{c_double, c_int, c_long, c_void, size_t, c_longlong};
 use std::slice::from_raw_parts;
 
+/// Create an empty sketch with given HyperMinHash parameters and hash function.
+/// Omitted parameters default to P=14, Q=6, R=10 and MurmurHash3.
+///
+/// `redis-cli> MH.RESERVE key [PRECISION p] [QBITS q] [RBITS r] [HASH murmur3|xxh3]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashReserve_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 2 || argc % 2 != 0 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let default = Params::default();
+        let (mut p, mut q, mut r) = (default.p(), default.q(), default.r());
+        let mut hash = HashAlgorithm::default();
+        for i in (2..argc).step_by(2) {
+            let name = arg_bytes(*argv.add(i as usize));
+            if name.eq_ignore_ascii_case(b"HASH") {
+                hash = match HashAlgorithm::from_name(arg_bytes(*argv.add(i as usize + 1))) {
+                    Some(hash) => hash,
+                    None => return reply_error(ctx, "ERR unknown hash function\0"),
+                };
+                continue;
+            }
+            let value = match arg_long(*argv.add(i as usize + 1)) {
+                Some(value) if value >= 0 => value as usize,
+                _ => return reply_error(ctx, "ERR value is not an integer or out of range\0"),
+            };
+
+            if name.eq_ignore_ascii_case(b"PRECISION") {
+                p = value;
+            } else if name.eq_ignore_ascii_case(b"QBITS") {
+                q = value;
+            } else if name.eq_ignore_ascii_case(b"RBITS") {
+                r = value;
+            } else {
+                return reply_error(ctx, "ERR syntax error\0");
+            }
+        }
+        let params = match Params::new(p, q, r) {
+            Some(params) => params,
+            None => return reply_error(ctx, "ERR invalid HyperMinHash parameters\0"),
+        };
+
+        let Key(key, key_type) = open_rw(ctx, *argv.add(1));
+        if key_type != REDISMODULE_KEYTYPE_EMPTY {
+            return reply_error(ctx, "ERR key already exists\0");
+        }
+        create_value(key, &params, hash);
+        RedisModule_ReplicateVerbatim(ctx);
+
+        reply_ok(ctx)
+    }
+}
+
 
 /// Add given elements to HyperMinHash sketch.
 /// Key will be initialized regardless of any element is passed or not.
@@ -27,55 +96,127 @@ pub extern "C" fn MinHashAdd_RedisCommand(
             return RedisModule_WrongArity(ctx);
         }
 
-        let Key(key, key_type) = open_rw(ctx, *argv.add(1));
+        add_to_sketch(ctx, *argv.add(1), |sketch| {
+            let mut updated = false;
+            for i in 2..argc {
+                updated |= sketch.add(arg_bytes(*argv.add(i as usize)));
+            }
+            updated
+        })
+    }
+}
+
+/// Split text into shingles server-side, and add each of them to HyperMinHash sketch as an element.
+/// SHINGLE option chooses the unit and length of shingles. (WORD 3 by default) See `Shingler` for details.
+/// LOWERCASE and NORMALIZE NFC lowercase and normalize text in this order before splitting.
+/// Key will be initialized regardless of any shingle is yielded or not.
+///
+/// `redis-cli> MH.ADDTEXT key text [SHINGLE WORD|CHAR k] [LOWERCASE] [NORMALIZE NFC]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashAddText_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
 
-        let mut new_key: bool = false;
-        if key_type != REDISMODULE_KEYTYPE_EMPTY && key_type != REDISMODULE_KEYTYPE_STRING {
-            return reply_wrong_type(ctx);
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 3 {
+            return RedisModule_WrongArity(ctx);
         }
-        if key_type == REDISMODULE_KEYTYPE_EMPTY {
-            if RedisModule_StringTruncate(key, HyperMinHashRepr::dense_len()) != REDISMODULE_OK {
-                return REDISMODULE_ERR;
+
+        let mut shingle = Shingle::default();
+        let mut lowercase = false;
+        let mut normalization = None;
+        let mut i = 3;
+        while i < argc {
+            let name = arg_bytes(*argv.add(i as usize));
+            if name.eq_ignore_ascii_case(b"LOWERCASE") {
+                lowercase = true;
+                i += 1;
+            } else if name.eq_ignore_ascii_case(b"SHINGLE") && i + 2 < argc {
+                let unit = arg_bytes(*argv.add(i as usize + 1));
+                let k = match arg_long(*argv.add(i as usize + 2)) {
+                    Some(k) if k > 0 => k as usize,
+                    _ => return reply_error(ctx, "ERR value is not an integer or out of range\0"),
+                };
+                shingle = if unit.eq_ignore_ascii_case(b"WORD") {
+                    Shingle::Word(k)
+                } else if unit.eq_ignore_ascii_case(b"CHAR") {
+                    Shingle::Char(k)
+                } else {
+                    return reply_error(ctx, "ERR syntax error\0");
+                };
+                i += 3;
+            } else if name.eq_ignore_ascii_case(b"NORMALIZE") && i + 1 < argc {
+                if !arg_bytes(*argv.add(i as usize + 1)).eq_ignore_ascii_case(b"NFC") {
+                    return reply_error(ctx, "ERR unknown normalization form\0");
+                }
+                normalization = Some(Normalization::Nfc);
+                i += 2;
+            } else {
+                return reply_error(ctx, "ERR syntax error\0");
             }
-            new_key = true
         }
+        // never fails since k is positive
+        let shingler = Shingler::new(shingle, lowercase, normalization).unwrap();
 
-        let mut bytes = string_dma(key);
+        let text = match std::str::from_utf8(arg_bytes(*argv.add(2))) {
+            Ok(text) => text,
+            Err(_) => return reply_error(ctx, "ERR text is not valid UTF-8\0"),
+        };
 
-        if new_key {
-            HyperMinHashRepr::initialize(&mut bytes);
-        }
-        match HyperMinHashRepr::parse(bytes) {
-            None => reply_wrong_type(ctx),
-            Some(mut repr) => {
-                let mut updated_count = 0;
-                let mut sketch = match repr.registers() {
-                    Registers::Dense(registers) => HyperMinHash::wrap(registers),
-                };
-                for i in 2..argc {
-                    let mut len: size_t = 0;
-                    let arg = RedisModule_StringPtrLen(*argv.add(i as usize), &mut len);
+        add_to_sketch(ctx, *argv.add(1), |sketch| shingler.add_to(sketch, text))
+    }
+}
 
-                    let updated = sketch.add(from_raw_parts(arg, len));
-                    if updated {
-                        updated_count += 1;
-                    }
-                }
-                if updated_count > 0 {
-                    repr.invalidate_cache();
-                    RedisModule_ReplicateVerbatim(ctx);
-                }
+/// Add given precomputed 128-bit hashes to HyperMinHash sketch.
+/// Each hash is either 16-byte binary (big endian) or 32-char hex string.
+/// Key will be initialized regardless of any hash is passed or not.
+///
+/// `redis-cli> MH.ADDHASH key [hash ...]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashAddHash_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
 
-                RedisModule_ReplyWithLongLong(ctx, if updated_count > 0 { 1 } else { 0 })
-            },
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 2 {
+            return RedisModule_WrongArity(ctx);
         }
+
+        // validate all hashes before updating the sketch
+        let mut hashes = Vec::with_capacity(argc as usize - 2);
+        for i in 2..argc {
+            match arg_hash(*argv.add(i as usize)) {
+                Some(hash) => hashes.push(hash),
+                None => return reply_error(ctx, "ERR hash must be 16-byte binary or 32-char hex string\0"),
+            }
+        }
+
+        add_to_sketch(ctx, *argv.add(1), |sketch| {
+            let mut updated = false;
+            for &hash in hashes.iter() {
+                updated |= sketch.add_hash(hash);
+            }
+            updated
+        })
     }
 }
 
 /// Estimate cardinality using HyperLogLog.
 /// If multiple keys are specified, estimate their union cardinality.
+/// Sketches built with different hash functions (e.g. HyperLogLog keys and sketches built by MH.ADD) are refused
+/// unless APPROX is specified, in which case they are unioned approximately, counting elements in both twice.
+/// ESTIMATOR option chooses the estimation algorithm. (IMPROVED by default)
+/// If CONFIDENCE is specified, replies the estimate with its lower and upper bounds at the level.
 ///
-/// `redis-cli> MH.COUNT key [key ...]`
+/// `redis-cli> MH.COUNT key [key ...] [ESTIMATOR ML|IMPROVED] [CONFIDENCE level] [APPROX]`
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn MinHashCount_RedisCommand(
@@ -91,32 +232,55 @@ pub extern "C" fn MinHashCount_RedisCommand(
         }
     }
 
-    // single key case
-    if argc == 2 {
-        unsafe {
-            let Key(key, key_type) = open_rw(ctx, *argv.add(1));
-
-            if key_type == REDISMODULE_KEYTYPE_EMPTY {
-                return RedisModule_ReplyWithLongLong(ctx, 0);
-            }
-            if key_type != REDISMODULE_KEYTYPE_STRING {
-                return reply_wrong_type(ctx);
+    let mut argc = argc;
+    let mut estimator = Estimator::default();
+    let mut confidence = None;
+    let mut approx = false;
+    loop {
+        if take_flag(argv, &mut argc, b"APPROX") {
+            approx = true;
+        } else if let Some(value) = take_option(argv, &mut argc, b"ESTIMATOR") {
+            let name = arg_bytes(value);
+            estimator = if name.eq_ignore_ascii_case(b"ML") {
+                Estimator::MaximumLikelihood
+            } else if name.eq_ignore_ascii_case(b"IMPROVED") {
+                Estimator::Improved
+            } else {
+                return reply_error(ctx, "ERR unknown estimator\0");
+            };
+        } else if let Some(value) = take_option(argv, &mut argc, b"CONFIDENCE") {
+            match arg_confidence(value) {
+                Some(level) => confidence = Some(level),
+                None => return reply_invalid_confidence(ctx),
             }
+        } else {
+            break;
+        }
+    }
+    if report_keys(ctx, 1..argc) {
+        return REDISMODULE_OK;
+    }
 
-            return match HyperMinHashRepr::parse(string_dma(key)) {
+    // single key case. cached cardinality is available only for default estimator and sketches
+    if argc == 2 && estimator == Estimator::Improved && !is_hll_key(ctx, unsafe { *argv.add(1) }) {
+        unsafe {
+            let value = match open_value(ctx, *argv.add(1)) {
+                Err(reply) => return reply,
+                Ok((_, None)) => return reply_cardinality(ctx, 0.0, &Params::default(), confidence),
+                Ok((_, Some(value))) => value,
+            };
+
+            return match HyperMinHashRepr::parse(value.bytes()) {
                 None =>
                     reply_wrong_type(ctx),
                 Some(mut repr) => {
                     if repr.cache_valid() {
-                        RedisModule_ReplyWithLongLong(ctx, repr.get_cache() as c_longlong)
+                        reply_cardinality(ctx, repr.get_cache() as f64, &repr.params(), confidence)
                     } else {
-                        let sketch = match repr.registers() {
-                            Registers::Dense(registers) => HyperMinHash::wrap(registers),
-                        };
-                        let cardinality = sketch.cardinality();
+                        let cardinality = repr.sketch().cardinality();
                         repr.set_cache(cardinality as u64);
                         RedisModule_ReplicateVerbatim(ctx);
-                        RedisModule_ReplyWithLongLong(ctx, cardinality as c_longlong)
+                        reply_cardinality(ctx, cardinality, &repr.params(), confidence)
                     }
                 },
             }
@@ -124,30 +288,58 @@ pub extern "C" fn MinHashCount_RedisCommand(
     }
 
     // multiple key case
-    let mut union_sketch = HyperMinHash::wrap(new_array_registers());
+    let mut sources = Vec::new();
     unsafe {
         for i in 1..argc {
-            let Key(key, key_type) = open_ro(ctx, *argv.add(i as usize));
-
-            if key_type == REDISMODULE_KEYTYPE_EMPTY {
-                continue;
-            }
-            if key_type != REDISMODULE_KEYTYPE_STRING {
-                return reply_wrong_type(ctx);
-            }
-
-            match HyperMinHashRepr::parse(string_dma(key)) {
-                None => return reply_wrong_type(ctx),
-                Some(repr) => {
-                    union_sketch.merge(&match repr.registers() {
-                        Registers::Dense(registers) => HyperMinHash::wrap(registers),
-                    });
+            match read_source(ctx, *argv.add(i as usize)) {
+                Err(reply) => return reply,
+                Ok(None) => continue,
+                Ok(Some(source)) => {
+                    // pinned estimate (e.g. by MH.INTERSECTSTORE) is more accurate than any estimator on its registers
+                    if let Source::Sketch(repr) = &source {
+                        if argc == 2 && repr.estimated() {
+                            return reply_cardinality(ctx, repr.get_cache() as f64, &repr.params(), confidence);
+                        }
+                    }
+                    sources.push(source);
                 },
             }
         }
+    }
+
+    match union_sources(&sources, approx) {
+        Err(Error::HashMismatch) =>
+            reply_error(ctx, "ERR sketches are built with different hash functions. Use APPROX to union them approximately, counting elements in both twice\0"),
+        Err(err) => reply_incompatible(ctx, err),
+        Ok(None) => reply_cardinality(ctx, 0.0, &Params::default(), confidence),
+        Ok(Some(sketch)) =>
+            reply_cardinality(ctx, sketch.cardinality_with(estimator), &sketch.params(), confidence),
+    }
+}
+
+/// Union sources into a new sketch. Returns None if no source is given.
+/// If `approx` is true, sources built with different hash functions are unioned by `merge_cardinality`.
+fn union_sources(sources: &[Source], approx: bool) -> Result<Option<HyperMinHash<ArrayRegisters, HashAlgorithm>>, Error> {
+    let (params, hash) = match sources.first() {
+        None => return Ok(None),
+        Some(source) => (source.params(), source.hash()),
+    };
+
+    let approximate = approx && sources.iter().any(|source| source.hash() != hash);
+    if !approximate {
+        let mut union = HyperMinHash::with_hasher(new_array_registers(&params), params, hash);
+        for source in sources {
+            source.merge_into(&mut union)?;
+        }
+        return Ok(Some(union));
+    }
 
-        RedisModule_ReplyWithLongLong(ctx, union_sketch.cardinality() as c_longlong)
+    // union shares only pattern lengths, so it has no MinHash-part regardless of the order of sources
+    let mut union = HyperMinHash::with_hasher(new_array_registers(&params), params, HashAlgorithm::RedisHll);
+    for source in sources {
+        source.merge_cardinality_into(&mut union)?;
     }
+    Ok(Some(union))
 }
 
 /// Merge multiple sketches into destination key.
@@ -168,56 +360,142 @@ pub extern "C" fn MinHashMerge_RedisCommand(
             return RedisModule_WrongArity(ctx);
         }
 
-        // handle target key
-        let Key(key, key_type) = open_rw(ctx, *argv.add(1));
+        // handle target key first,
+        // since it may be converted from plain string while it's also specified as a source
+        let (key, existing) = match open_value(ctx, *argv.add(1)) {
+            Err(reply) => return reply,
+            Ok(opened) => opened,
+        };
 
-        let mut new_key: bool = false;
-        if key_type != REDISMODULE_KEYTYPE_EMPTY && key_type != REDISMODULE_KEYTYPE_STRING {
-            return reply_wrong_type(ctx);
-        }
-        if key_type == REDISMODULE_KEYTYPE_EMPTY {
-            if RedisModule_StringTruncate(key, HyperMinHashRepr::dense_len()) != REDISMODULE_OK {
-                return REDISMODULE_ERR;
+        // collect source sketches. HyperLogLog sources are converted into sketches
+        let mut sources = Vec::new();
+        for i in 2..argc {
+            match read_source(ctx, *argv.add(i as usize)) {
+                Err(reply) => return reply,
+                Ok(None) => continue,
+                Ok(Some(source)) => sources.push(source),
             }
-            new_key = true;
         }
 
-        let mut bytes = string_dma(key);
-
-        if new_key {
-            HyperMinHashRepr::initialize(&mut bytes);
-        }
+        let value = match existing {
+            Some(value) => value,
+            None => {
+                // new destination key inherits parameters and hash function from the first source
+                let (params, hash) = match sources.first() {
+                    None => (Params::default(), HashAlgorithm::default()),
+                    Some(first) => {
+                        let (params, hash) = (first.params(), first.hash());
+                        if let Err(err) = sources.iter().try_for_each(|source| source.compatible(&params, hash)) {
+                            return reply_merge_incompatible(ctx, err, &sources, hash);
+                        }
+                        (params, hash)
+                    },
+                };
+                create_value(key, &params, hash)
+            },
+        };
 
-        let mut repr = match HyperMinHashRepr::parse(bytes) {
+        let mut repr = match HyperMinHashRepr::parse(value.bytes()) {
             None =>
                 return reply_wrong_type(ctx),
             Some(repr) => repr,
         };
-        let mut union_sketch = match repr.registers() {
-            Registers::Dense(registers) => HyperMinHash::wrap(registers),
-        };
+        // check all sources beforehand since dense registers are updated in place
+        if let Err(err) = sources.iter().try_for_each(|source| source.compatible(&repr.params(), repr.hash())) {
+            return reply_merge_incompatible(ctx, err, &sources, repr.hash());
+        }
+
+        let mut union_sketch = repr.sketch();
+        for source in sources.iter() {
+            if let Err(err) = source.merge_into(&mut union_sketch) {
+                return reply_incompatible(ctx, err);
+            }
+        }
+        repr.invalidate_cache();
+        // dense registers are updated in place
+        if let Registers::Sparse(registers) = &union_sketch.registers {
+            store_sparse(value, &union_sketch.params(), registers);
+        }
+        RedisModule_ReplicateVerbatim(ctx);
+
+        reply_ok(ctx)
+    }
+}
+
+/// Convert Redis built-in HyperLogLog keys into a sketch, and store their union into destination key.
+/// Existing value of destination key is overwritten. Non-existent keys are regarded as empty.
+/// The sketch has MinHash bits unknown, so it supports only cardinality estimation. (e.g. MH.COUNT, MH.MERGE)
+///
+/// `redis-cli> MH.FROMHLL destkey hllkey [hllkey ...]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashFromHll_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
 
+        if argc < 3 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let params = Params::default();
+        let mut union_sketch = HyperMinHash::with_hasher(new_array_registers(&params), params, HashAlgorithm::RedisHll);
         for i in 2..argc {
             let Key(key, key_type) = open_ro(ctx, *argv.add(i as usize));
-
-            if key_type == REDISMODULE_KEYTYPE_EMPTY {
-                continue;
-            }
-            if key_type != REDISMODULE_KEYTYPE_STRING {
-                return reply_wrong_type(ctx);
+            let sketch = match key_type {
+                REDISMODULE_KEYTYPE_EMPTY => continue,
+                REDISMODULE_KEYTYPE_STRING => HyperMinHash::from_hll(string_dma(key).as_slice()),
+                _ => None,
+            };
+            match sketch {
+                None => return reply_error(ctx, "WRONGTYPE Key is not a valid HyperLogLog string value.\0"),
+                // never fails since all sketches have the same parameters and hash function
+                Some(sketch) => union_sketch.merge(&sketch).unwrap(),
             }
+        }
 
-            match HyperMinHashRepr::parse(string_dma(key)) {
-                None =>
-                    return reply_wrong_type(ctx),
-                Some(repr) => {
-                    union_sketch.merge(&match repr.registers() {
-                        Registers::Dense(registers) => HyperMinHash::wrap(registers),
-                    });
-                },
-            }
+        let Key(key, _) = open_rw(ctx, *argv.add(1));
+        set_value(key, SketchValue::wrap(union_sketch.to_bytes()));
+        RedisModule_ReplicateVerbatim(ctx);
+
+        reply_ok(ctx)
+    }
+}
+
+/// Export the sketch as Redis built-in HyperLogLog into destination key, overwriting existing value.
+/// Pattern lengths of registers are capped to the range of HyperLogLog registers, so that PFCOUNT agrees with MH.COUNT.
+/// Only sketches with PRECISION 14 can be exported. Non-existent key is regarded as empty.
+///
+/// `redis-cli> MH.TOHLL destkey key`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashToHll_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 3 {
+            return RedisModule_WrongArity(ctx);
         }
-        repr.invalidate_cache();
+
+        let hll = match read_repr(ctx, *argv.add(2)) {
+            Err(reply) => return reply,
+            Ok(None) => HyperMinHash::wrap(new_array_registers(&Params::default())).to_hll(),
+            Ok(Some(repr)) => repr.sketch().to_hll(),
+        };
+        let hll = match hll {
+            None => return reply_error(ctx, "ERR only sketches with PRECISION 14 can be exported as HyperLogLog\0"),
+            Some(hll) => hll,
+        };
+
+        let Key(key, _) = open_rw(ctx, *argv.add(1));
+        RedisModule_StringSet(key, RedisModule_CreateString(ctx, hll.as_ptr(), hll.len()));
         RedisModule_ReplicateVerbatim(ctx);
 
         reply_ok(ctx)
@@ -225,8 +503,9 @@ pub extern "C" fn MinHashMerge_RedisCommand(
 }
 
 /// Estimate similarity between multiple sketches using MinHash.
+/// If CONFIDENCE is specified, replies the estimate with its lower and upper bounds at the level.
 ///
-/// `redis-cli> MH.SIMILARITY key [key ...]`
+/// `redis-cli> MH.SIMILARITY key [key ...] [CONFIDENCE level]`
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn MinHashSimilarity_RedisCommand(
@@ -241,35 +520,39 @@ pub extern "C" fn MinHashSimilarity_RedisCommand(
             return RedisModule_WrongArity(ctx);
         }
 
-        let mut combiner = MinHashCombiner::new();
-        for i in 1..argc {
-            let Key(key, key_type) = open_ro(ctx, *argv.add(i as usize));
+        let mut argc = argc;
+        let confidence = take_option(argv, &mut argc, b"CONFIDENCE");
+        if report_keys(ctx, 1..argc) {
+            return REDISMODULE_OK;
+        }
+        let confidence = match confidence.map(arg_confidence) {
+            None => None,
+            Some(None) => return reply_invalid_confidence(ctx),
+            Some(level) => level,
+        };
 
-            if key_type == REDISMODULE_KEYTYPE_EMPTY {
-                continue;
-            }
-            if key_type != REDISMODULE_KEYTYPE_STRING {
-                return reply_wrong_type(ctx);
-            }
+        let combiner = match combine_keys(ctx, argv, argc) {
+            Err(reply) => return reply,
+            Ok(combiner) => combiner,
+        };
 
-            match HyperMinHashRepr::parse(string_dma(key)) {
-                None =>
-                    return reply_wrong_type(ctx),
-                Some(repr) => {
-                    combiner.combine(&match repr.registers() {
-                        Registers::Dense(registers) => HyperMinHash::wrap(registers),
-                    })
-                },
-            }
+        match confidence {
+            None => RedisModule_ReplyWithDouble(ctx, combiner.similarity() as c_double),
+            Some(level) => {
+                let interval = combiner.similarity_interval(level);
+                RedisModule_ReplyWithArray(ctx, 3);
+                RedisModule_ReplyWithDouble(ctx, interval.estimate as c_double);
+                RedisModule_ReplyWithDouble(ctx, interval.lower as c_double);
+                RedisModule_ReplyWithDouble(ctx, interval.upper as c_double)
+            },
         }
-
-        RedisModule_ReplyWithDouble(ctx, combiner.similarity() as c_double)
     }
 }
 
 /// Estimate intersection cardinality of multiple sketches using MinHash.
+/// If CONFIDENCE is specified, replies the estimate with its lower and upper bounds at the level.
 ///
-/// `redis-cli> MH.INTERSECTION key [key ...]`
+/// `redis-cli> MH.INTERSECTION key [key ...] [CONFIDENCE level]`
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn MinHashIntersection_RedisCommand(
@@ -284,64 +567,1574 @@ pub extern "C" fn MinHashIntersection_RedisCommand(
             return RedisModule_WrongArity(ctx);
         }
 
-        let mut combiner = MinHashCombiner::new();
-        for i in 1..argc {
-            let Key(key, key_type) = open_ro(ctx, *argv.add(i as usize));
+        let mut argc = argc;
+        let confidence = take_option(argv, &mut argc, b"CONFIDENCE");
+        if report_keys(ctx, 1..argc) {
+            return REDISMODULE_OK;
+        }
+        let confidence = match confidence.map(arg_confidence) {
+            None => None,
+            Some(None) => return reply_invalid_confidence(ctx),
+            Some(level) => level,
+        };
 
-            if key_type == REDISMODULE_KEYTYPE_EMPTY {
-                continue;
-            }
-            if key_type != REDISMODULE_KEYTYPE_STRING {
-                return reply_wrong_type(ctx);
-            }
+        let combiner = match combine_keys(ctx, argv, argc) {
+            Err(reply) => return reply,
+            Ok(combiner) => combiner,
+        };
 
-            match HyperMinHashRepr::parse(string_dma(key)) {
-                None =>
-                    return reply_wrong_type(ctx),
-                Some(repr) => {
-                    combiner.combine(&match repr.registers() {
-                        Registers::Dense(registers) => HyperMinHash::wrap(registers),
-                    })
+        match confidence {
+            None => RedisModule_ReplyWithLongLong(ctx, combiner.intersection().round() as c_longlong),
+            Some(level) => reply_interval(ctx, &combiner.intersection_interval(level)),
+        }
+    }
+}
+
+/// Store the approximate intersection of multiple sketches into destination key, overwriting existing value.
+/// Destination retains only registers equal across all source sketches, and its cardinality is pinned to
+/// the intersection estimate until the destination is updated. Non-existent keys are regarded as empty.
+/// Replies the intersection estimate.
+///
+/// `redis-cli> MH.INTERSECTSTORE destkey key [key ...]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashIntersectStore_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 3 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let mut combiner = MinHashCombiner::new();
+        let mut hash = None;
+        let mut missing = false;
+        for i in 2..argc {
+            match read_repr(ctx, *argv.add(i as usize)) {
+                Err(reply) => return reply,
+                Ok(None) => missing = true,
+                Ok(Some(repr)) => {
+                    if let Err(err) = combiner.combine(&repr.sketch()) {
+                        return reply_incompatible(ctx, err);
+                    }
+                    hash.get_or_insert(repr.hash());
                 },
             }
         }
 
-        RedisModule_ReplyWithLongLong(ctx, combiner.intersection().round() as c_longlong)
+        // build before opening destination, since it may be also a source
+        let params = combiner.params();
+        let (mut value, intersection) = match hash {
+            Some(hash) if !missing => (
+                SketchValue::wrap(HyperMinHashRepr::to_vec(&params, &hash, combiner.intersection_registers())),
+                combiner.intersection().max(0.0)),
+            // intersection with an empty set is empty
+            _ => (
+                SketchValue::wrap(HyperMinHashRepr::to_vec(&params, &hash.unwrap_or_default(), &new_array_registers(&params))),
+                0.0),
+        };
+        value.set_estimate(intersection.round() as u64);
+
+        let Key(key, _) = open_rw(ctx, *argv.add(1));
+        set_value(key, value);
+        RedisModule_ReplicateVerbatim(ctx);
+
+        RedisModule_ReplyWithLongLong(ctx, intersection.round() as c_longlong)
     }
 }
 
-struct Key(*mut RedisModuleKey, c_int);
+/// Report all set-overlap metrics of multiple sketches at once.
+/// Replies flat array of name and value pairs. Per-key values are in the order of given keys.
+/// Non-existent keys are regarded as empty.
+///
+/// `redis-cli> MH.STATS key [key ...]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashStats_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
 
-fn open_ro(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString) -> Key {
     unsafe {
-        let ptr = RedisModule_OpenKey(ctx, string, REDISMODULE_READ);
-        let key_type = RedisModule_KeyType(ptr);
+        RedisModule_AutoMemory(ctx);
 
-        Key(ptr, key_type)
+        if argc < 2 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        // whether each key exists, to align per-key values with given keys
+        let mut exists = Vec::with_capacity(argc as usize - 1);
+        let mut combiner = MinHashCombiner::new();
+        for i in 1..argc {
+            match read_repr(ctx, *argv.add(i as usize)) {
+                Err(reply) => return reply,
+                Ok(None) => exists.push(false),
+                Ok(Some(repr)) => {
+                    if let Err(err) = combiner.combine(&repr.sketch()) {
+                        return reply_incompatible(ctx, err);
+                    }
+                    exists.push(true);
+                },
+            }
+        }
+
+        let estimates = stats_estimates(&combiner, &exists);
+
+        RedisModule_ReplyWithArray(ctx, 14);
+
+        RedisModule_ReplyWithSimpleString(ctx, "cardinalities\0".as_ptr());
+        RedisModule_ReplyWithArray(ctx, exists.len() as c_long);
+        for &cardinality in &estimates.cardinalities {
+            RedisModule_ReplyWithLongLong(ctx, cardinality as c_longlong);
+        }
+
+        RedisModule_ReplyWithSimpleString(ctx, "union\0".as_ptr());
+        RedisModule_ReplyWithLongLong(ctx, estimates.union as c_longlong);
+
+        RedisModule_ReplyWithSimpleString(ctx, "similarity\0".as_ptr());
+        RedisModule_ReplyWithDouble(ctx, estimates.similarity as c_double);
+
+        RedisModule_ReplyWithSimpleString(ctx, "intersection\0".as_ptr());
+        RedisModule_ReplyWithLongLong(ctx, estimates.intersection.round() as c_longlong);
+
+        RedisModule_ReplyWithSimpleString(ctx, "containments\0".as_ptr());
+        RedisModule_ReplyWithArray(ctx, exists.len() as c_long);
+        for &containment in &estimates.containments {
+            RedisModule_ReplyWithDouble(ctx, containment as c_double);
+        }
+
+        RedisModule_ReplyWithSimpleString(ctx, "dice\0".as_ptr());
+        RedisModule_ReplyWithDouble(ctx, estimates.dice as c_double);
+
+        RedisModule_ReplyWithSimpleString(ctx, "overlap\0".as_ptr());
+        RedisModule_ReplyWithDouble(ctx, estimates.overlap as c_double)
     }
 }
 
-fn open_rw(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString) -> Key {
-    unsafe {
-        let ptr = RedisModule_OpenKey(ctx, string, REDISMODULE_READ | REDISMODULE_WRITE);
-        let key_type = RedisModule_KeyType(ptr);
+/// Estimates of MH.STATS with per-key values aligned with given keys, where `exists` tells whether each key exists.
+/// A non-existent key is an empty set, which makes the intersection and all the metrics derived from it zero.
+fn stats_estimates(combiner: &MinHashCombiner, exists: &[bool]) -> Estimates {
+    let mut estimates = combiner.estimates();
+    let mut cardinalities = estimates.cardinalities.iter();
+    estimates.cardinalities = exists.iter()
+        .map(|&exists| if exists { *cardinalities.next().unwrap() } else { 0.0 })
+        .collect();
 
-        Key(ptr, key_type)
+    if exists.iter().all(|&exists| exists) {
+        return estimates;
     }
-}
 
-fn string_dma(key: *mut RedisModuleKey) -> CByteArray {
-    let mut len: size_t = 0;
-    unsafe {
-        let ptr = RedisModule_StringDMA(key, &mut len, REDISMODULE_WRITE);
-        CByteArray::wrap(ptr, len)
+    Estimates {
+        similarity: 0.0,
+        intersection: 0.0,
+        containments: vec![0.0; exists.len()],
+        dice: 0.0,
+        overlap: 0.0,
+        ..estimates
     }
 }
 
-fn reply_wrong_type(ctx: *mut RedisModuleCtx) -> c_int {
+/// Estimate cardinality of set-algebra expression over given keys.
+/// Operands of the expression are key names, which have to be given as arguments.
+/// `|`, `&`, `-` denote union, intersection and difference respectively. Non-existent keys are regarded as empty.
+/// If CONFIDENCE is specified, replies the estimate with its lower and upper bounds at the level.
+///
+/// `redis-cli> MH.EVAL expr key [key ...] [CONFIDENCE level]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashEval_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        let mut argc = argc;
+        let confidence = take_option(argv, &mut argc, b"CONFIDENCE");
+        if report_keys(ctx, 2..argc) {
+            return REDISMODULE_OK;
+        }
+        let confidence = match confidence.map(arg_confidence) {
+            None => None,
+            Some(None) => return reply_invalid_confidence(ctx),
+            Some(level) => level,
+        };
+
+        if argc < 3 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let keys: Vec<_> = (2..argc).map(|i| *argv.add(i as usize)).collect();
+        let expr = Expr::parse(arg_bytes(*argv.add(1)), |name| {
+            keys.iter().position(|&key| arg_bytes(key) == name)
+        });
+        let expr = match expr {
+            Ok(expr) => expr,
+            Err(ParseError::Syntax) =>
+                return reply_error(ctx, "ERR syntax error in expression\0"),
+            Err(ParseError::UnknownOperand(_)) =>
+                return reply_error(ctx, "ERR expression refers to a key which is not given\0"),
+        };
+
+        let mut reprs = Vec::with_capacity(keys.len());
+        for &key in keys.iter() {
+            match read_repr(ctx, key) {
+                Err(reply) => return reply,
+                Ok(repr) => reprs.push(repr),
+            }
+        }
+        let sketches: Vec<_> = reprs.iter().map(|repr| repr.as_ref().map(|repr| repr.sketch())).collect();
+        let operands: Vec<_> = sketches.iter().map(|sketch| sketch.as_ref()).collect();
+
+        let estimate = match expr.estimate(&operands) {
+            Err(err) => return reply_incompatible(ctx, err),
+            Ok(estimate) => estimate,
+        };
+
+        match confidence {
+            None => RedisModule_ReplyWithLongLong(ctx, estimate.cardinality().round() as c_longlong),
+            Some(level) => reply_interval(ctx, &estimate.interval(level)),
+        }
+    }
+}
+
+/// Pairwise estimation among multiple sketches.
+/// Replies flat N×N array in row-major order. For containment, (i, j) element is the fraction of i-th key contained in j-th key.
+/// Non-existent keys are regarded as empty.
+///
+/// `redis-cli> MH.MATRIX METRIC JACCARD|INTERSECTION|CONTAINMENT key [key ...]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashMatrix_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 4 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        if !arg_bytes(*argv.add(1)).eq_ignore_ascii_case(b"METRIC") {
+            return reply_error(ctx, "ERR syntax error\0");
+        }
+        let name = arg_bytes(*argv.add(2));
+        let metric = if name.eq_ignore_ascii_case(b"JACCARD") {
+            Metric::Jaccard
+        } else if name.eq_ignore_ascii_case(b"INTERSECTION") {
+            Metric::Intersection
+        } else if name.eq_ignore_ascii_case(b"CONTAINMENT") {
+            Metric::Containment
+        } else {
+            return reply_error(ctx, "ERR unknown metric\0");
+        };
+
+        let mut reprs = Vec::with_capacity(argc as usize - 3);
+        for i in 3..argc {
+            match read_repr(ctx, *argv.add(i as usize)) {
+                Err(reply) => return reply,
+                Ok(repr) => reprs.push(repr),
+            }
+        }
+        let sketches: Vec<_> = reprs.iter().map(|repr| repr.as_ref().map(|repr| repr.sketch())).collect();
+        let operands: Vec<_> = sketches.iter().map(|sketch| sketch.as_ref()).collect();
+
+        let matrix = match pairwise_matrix(&operands, metric) {
+            Err(err) => return reply_incompatible(ctx, err),
+            Ok(matrix) => matrix,
+        };
+
+        RedisModule_ReplyWithArray(ctx, matrix.len() as c_long);
+        for value in matrix {
+            match metric {
+                Metric::Intersection => RedisModule_ReplyWithLongLong(ctx, value.round() as c_longlong),
+                _ => RedisModule_ReplyWithDouble(ctx, value as c_double),
+            };
+        }
+        REDISMODULE_OK
+    }
+}
+
+/// Find K keys most similar to the query key.
+/// Replies flat array of key and score pairs in descending order of score, like ZRANGE WITHSCORES.
+/// METRIC option chooses the score. (JACCARD by default) Non-existent keys are skipped.
+///
+/// `redis-cli> MH.TOPK query_key K key [key ...] [METRIC JACCARD|INTERSECTION]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashTopK_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        let mut argc = argc;
+        let metric = take_option(argv, &mut argc, b"METRIC");
+        // K at argv[2] is not a key
+        if report_keys(ctx, std::iter::once(1).chain(3..argc)) {
+            return REDISMODULE_OK;
+        }
+        let metric = match metric.map(arg_bytes) {
+            None => Metric::Jaccard,
+            Some(name) if name.eq_ignore_ascii_case(b"JACCARD") => Metric::Jaccard,
+            Some(name) if name.eq_ignore_ascii_case(b"INTERSECTION") => Metric::Intersection,
+            Some(_) => return reply_error(ctx, "ERR unknown metric\0"),
+        };
+
+        if argc < 4 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let k = match arg_long(*argv.add(2)) {
+            Some(k) if k > 0 => k as usize,
+            _ => return reply_error(ctx, "ERR K must be a positive integer\0"),
+        };
+
+        let query = match read_repr(ctx, *argv.add(1)) {
+            Err(reply) => return reply,
+            // nothing is similar to an empty set
+            Ok(None) => return RedisModule_ReplyWithArray(ctx, 0),
+            Ok(Some(repr)) => repr,
+        };
+
+        let mut reprs = Vec::with_capacity(argc as usize - 3);
+        for i in 3..argc {
+            match read_repr(ctx, *argv.add(i as usize)) {
+                Err(reply) => return reply,
+                Ok(repr) => reprs.push(repr),
+            }
+        }
+        let sketches: Vec<_> = reprs.iter().map(|repr| repr.as_ref().map(|repr| repr.sketch())).collect();
+        let candidates: Vec<_> = sketches.iter().map(|sketch| sketch.as_ref()).collect();
+
+        let result = match top_k(&query.sketch(), &candidates, k, metric) {
+            Err(err) => return reply_incompatible(ctx, err),
+            Ok(result) => result,
+        };
+
+        RedisModule_ReplyWithArray(ctx, result.len() as c_long * 2);
+        for (index, score) in result {
+            RedisModule_ReplyWithString(ctx, *argv.add(index + 3));
+            match metric {
+                Metric::Intersection => RedisModule_ReplyWithLongLong(ctx, score.round() as c_longlong),
+                _ => RedisModule_ReplyWithDouble(ctx, score as c_double),
+            };
+        }
+        REDISMODULE_OK
+    }
+}
+
+/// Group keys into single-linkage clusters, i.e. connected components under estimated Jaccard index at least the threshold.
+/// Candidate pairs are pruned by LSH instead of comparing all pairs.
+/// Replies array of clusters, each of which is an array of keys, ordered by cluster size descending.
+/// Non-existent and empty keys are singletons.
+///
+/// `redis-cli> MH.CLUSTER THRESHOLD t key [key ...]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashCluster_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 4 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        if !arg_bytes(*argv.add(1)).eq_ignore_ascii_case(b"THRESHOLD") {
+            return reply_error(ctx, "ERR syntax error\0");
+        }
+        let threshold = match arg_double(*argv.add(2)) {
+            Some(threshold) if threshold > 0.0 && threshold <= 1.0 => threshold,
+            _ => return reply_error(ctx, "ERR threshold must be greater than 0 and at most 1\0"),
+        };
+
+        let mut reprs = Vec::with_capacity(argc as usize - 3);
+        for i in 3..argc {
+            match read_repr(ctx, *argv.add(i as usize)) {
+                Err(reply) => return reply,
+                Ok(repr) => reprs.push(repr),
+            }
+        }
+        let sketches: Vec<_> = reprs.iter().map(|repr| repr.as_ref().map(|repr| repr.sketch())).collect();
+        let operands: Vec<_> = sketches.iter().map(|sketch| sketch.as_ref()).collect();
+
+        let clusters = match cluster(&operands, threshold) {
+            Err(err) => return reply_incompatible(ctx, err),
+            Ok(clusters) => clusters,
+        };
+
+        RedisModule_ReplyWithArray(ctx, clusters.len() as c_long);
+        for members in clusters {
+            RedisModule_ReplyWithArray(ctx, members.len() as c_long);
+            for index in members {
+                RedisModule_ReplyWithString(ctx, *argv.add(index + 3));
+            }
+        }
+        REDISMODULE_OK
+    }
+}
+
+/// Estimate cumulative intersection cardinality of each step of a funnel, i.e. |key1|, |key1∩key2|, ...
+/// Replies array of integers in the order of given keys. Non-existent keys are regarded as empty.
+///
+/// `redis-cli> MH.FUNNEL key [key ...]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashFunnel_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 2 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let mut reprs = Vec::with_capacity(argc as usize - 1);
+        for i in 1..argc {
+            match read_repr(ctx, *argv.add(i as usize)) {
+                Err(reply) => return reply,
+                Ok(repr) => reprs.push(repr),
+            }
+        }
+        let sketches: Vec<_> = reprs.iter().map(|repr| repr.as_ref().map(|repr| repr.sketch())).collect();
+        let steps: Vec<_> = sketches.iter().map(|sketch| sketch.as_ref()).collect();
+
+        let result = match funnel(&steps) {
+            Err(err) => return reply_incompatible(ctx, err),
+            Ok(result) => result,
+        };
+
+        RedisModule_ReplyWithArray(ctx, result.len() as c_long);
+        for value in result {
+            RedisModule_ReplyWithLongLong(ctx, value.round() as c_longlong);
+        }
+        REDISMODULE_OK
+    }
+}
+
+/// Estimate retained, new and lost ids between two periods, and the retention rate.
+/// Replies flat array of name and value pairs. Non-existent keys are regarded as empty.
+///
+/// `redis-cli> MH.CHURN previous current`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashChurn_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 3 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let previous = match read_repr(ctx, *argv.add(1)) {
+            Err(reply) => return reply,
+            Ok(repr) => repr,
+        };
+        let current = match read_repr(ctx, *argv.add(2)) {
+            Err(reply) => return reply,
+            Ok(repr) => repr,
+        };
+
+        let result = match churn(
+            previous.as_ref().map(|repr| repr.sketch()).as_ref(),
+            current.as_ref().map(|repr| repr.sketch()).as_ref()) {
+            Err(err) => return reply_incompatible(ctx, err),
+            Ok(result) => result,
+        };
+
+        RedisModule_ReplyWithArray(ctx, 8);
+
+        RedisModule_ReplyWithSimpleString(ctx, "retained\0".as_ptr());
+        RedisModule_ReplyWithLongLong(ctx, result.retained.round() as c_longlong);
+
+        RedisModule_ReplyWithSimpleString(ctx, "new\0".as_ptr());
+        RedisModule_ReplyWithLongLong(ctx, result.new.round() as c_longlong);
+
+        RedisModule_ReplyWithSimpleString(ctx, "lost\0".as_ptr());
+        RedisModule_ReplyWithLongLong(ctx, result.lost.round() as c_longlong);
+
+        RedisModule_ReplyWithSimpleString(ctx, "retention\0".as_ptr());
+        RedisModule_ReplyWithDouble(ctx, result.retention as c_double)
+    }
+}
+
+/// Reply MinHash signature of the sketch, i.e. the low RBITS bits of each register, in the order of registers.
+/// FORMAT INT (default) and HEX reply an array of integers or zero-padded hex strings, with nil for empty registers.
+/// FORMAT BINARY replies values packed from the most significant bit, where empty registers are zero.
+/// BBITS compresses each value into its lowest b bits. (b-bit minwise hashing)
+/// Replies nil if the key doesn't exist.
+///
+/// `redis-cli> MH.SIGNATURE key [FORMAT int|hex|binary] [BBITS b]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashSignature_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 2 || argc % 2 != 0 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let mut format = SignatureFormat::Int;
+        let mut b_bits = None;
+        for i in (2..argc).step_by(2) {
+            let name = arg_bytes(*argv.add(i as usize));
+            let value = *argv.add(i as usize + 1);
+            if name.eq_ignore_ascii_case(b"FORMAT") {
+                format = match SignatureFormat::from_name(arg_bytes(value)) {
+                    Some(format) => format,
+                    None => return reply_error(ctx, "ERR unknown format\0"),
+                };
+            } else if name.eq_ignore_ascii_case(b"BBITS") {
+                b_bits = match arg_long(value) {
+                    Some(b) if b >= 0 => Some(b as usize),
+                    _ => return reply_error(ctx, "ERR value is not an integer or out of range\0"),
+                };
+            } else {
+                return reply_error(ctx, "ERR syntax error\0");
+            }
+        }
+
+        let signature = match read_repr(ctx, *argv.add(1)) {
+            Err(reply) => return reply,
+            Ok(None) => return RedisModule_ReplyWithNull(ctx),
+            Ok(Some(repr)) => match repr.sketch().signature() {
+                Err(err) => return reply_incompatible(ctx, err),
+                Ok(signature) => signature,
+            },
+        };
+        let signature = match b_bits {
+            None => signature,
+            Some(b) => match signature.compress(b) {
+                None => return reply_error(ctx, "ERR BBITS must be between 1 and RBITS of the sketch\0"),
+                Some(compressed) => compressed,
+            },
+        };
+
+        match format {
+            SignatureFormat::Binary => {
+                let bytes = signature.to_bytes();
+                RedisModule_ReplyWithStringBuffer(ctx, bytes.as_ptr(), bytes.len())
+            },
+            SignatureFormat::Int | SignatureFormat::Hex => {
+                let width = signature.bits().div_ceil(4);
+                RedisModule_ReplyWithArray(ctx, signature.values().len() as c_long);
+                for value in signature.values() {
+                    match value {
+                        None => RedisModule_ReplyWithNull(ctx),
+                        Some(value) if format == SignatureFormat::Int =>
+                            RedisModule_ReplyWithLongLong(ctx, c_longlong::from(*value)),
+                        Some(value) => {
+                            let hex = format!("{:0width$x}", value, width = width);
+                            RedisModule_ReplyWithStringBuffer(ctx, hex.as_ptr(), hex.len())
+                        },
+                    };
+                }
+                REDISMODULE_OK
+            },
+        }
+    }
+}
+
+/// Serialize the sketch into portable binary format which can be restored by MH.RESTORE.
+/// Replies nil if the key doesn't exist.
+///
+/// `redis-cli> MH.DUMP key`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashDump_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 2 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        match read_repr(ctx, *argv.add(1)) {
+            Err(reply) => reply,
+            Ok(None) => RedisModule_ReplyWithNull(ctx),
+            Ok(Some(repr)) => {
+                let blob = dump::dump(&repr);
+                RedisModule_ReplyWithStringBuffer(ctx, blob.as_ptr(), blob.len())
+            },
+        }
+    }
+}
+
+/// Create a sketch from the payload produced by MH.DUMP.
+/// Fails if the key already exists unless REPLACE is specified.
+///
+/// `redis-cli> MH.RESTORE key serialized-value [REPLACE]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashRestore_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 3 && argc != 4 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let replace = if argc == 4 {
+            if !arg_bytes(*argv.add(3)).eq_ignore_ascii_case(b"REPLACE") {
+                return reply_error(ctx, "ERR syntax error\0");
+            }
+            true
+        } else {
+            false
+        };
+
+        let value = match dump::restore(arg_bytes(*argv.add(2))) {
+            Err(err) => return reply_error(ctx, err.message()),
+            Ok(value) => value,
+        };
+
+        let Key(key, key_type) = open_rw(ctx, *argv.add(1));
+        if key_type != REDISMODULE_KEYTYPE_EMPTY && !replace {
+            return reply_error(ctx, "BUSYKEY Target key name already exists.\0");
+        }
+        set_value(key, value);
+        RedisModule_ReplicateVerbatim(ctx);
+
+        reply_ok(ctx)
+    }
+}
+
+/// Merge a sketch serialized by another library into the key, creating it with default parameters if it doesn't exist.
+/// Only `liveramp` format (BetaMinHash of LiveRamp HyperMinHash-java) is supported.
+///
+/// `redis-cli> MH.IMPORT key FORMAT liveramp blob`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashImport_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 5 {
+            return RedisModule_WrongArity(ctx);
+        }
+        if let Err(reply) = arg_format(ctx, *argv.add(2), *argv.add(3)) {
+            return reply;
+        }
+        let imported = match HyperMinHash::from_liveramp(arg_bytes(*argv.add(4))) {
+            None => return reply_error(ctx, "ERR invalid LiveRamp HyperMinHash payload\0"),
+            Some(imported) => imported,
+        };
+
+        let (key, existing) = match open_value(ctx, *argv.add(1)) {
+            Err(reply) => return reply,
+            Ok(opened) => opened,
+        };
+        let value = match existing {
+            Some(value) => value,
+            None => create_value(key, &imported.params(), HashAlgorithm::Murmur3),
+        };
+
+        let mut repr = match HyperMinHashRepr::parse(value.bytes()) {
+            None => return reply_wrong_type(ctx),
+            Some(repr) => repr,
+        };
+        let mut sketch = repr.sketch();
+        // compatibility is checked before dense registers are updated in place
+        if let Err(err) = sketch.merge(&imported) {
+            return reply_incompatible(ctx, err);
+        }
+        repr.invalidate_cache();
+        if let Registers::Sparse(registers) = &sketch.registers {
+            store_sparse(value, &sketch.params(), registers);
+        }
+        RedisModule_ReplicateVerbatim(ctx);
+
+        reply_ok(ctx)
+    }
+}
+
+/// Serialize the sketch into the format of another library. Replies nil if the key doesn't exist.
+/// Only `liveramp` format (BetaMinHash of LiveRamp HyperMinHash-java) is supported,
+/// which requires default parameters (P=14, Q=6, R=10) and MurmurHash3.
+///
+/// `redis-cli> MH.EXPORT key FORMAT liveramp`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashExport_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 4 {
+            return RedisModule_WrongArity(ctx);
+        }
+        if let Err(reply) = arg_format(ctx, *argv.add(2), *argv.add(3)) {
+            return reply;
+        }
+
+        match read_repr(ctx, *argv.add(1)) {
+            Err(reply) => reply,
+            Ok(None) => RedisModule_ReplyWithNull(ctx),
+            Ok(Some(repr)) => match repr.sketch().to_liveramp() {
+                Err(Error::HashMismatch) =>
+                    reply_error(ctx, "ERR LiveRamp format supports only murmur3 hash function\0"),
+                Err(_) =>
+                    reply_error(ctx, "ERR LiveRamp format supports only PRECISION 14, QBITS 6 and RBITS 10\0"),
+                Ok(blob) => RedisModule_ReplyWithStringBuffer(ctx, blob.as_ptr(), blob.len()),
+            },
+        }
+    }
+}
+
+/// Create an empty MinHash LSH index.
+/// bands * rows must not exceed the number of registers of indexed sketches.
+///
+/// `redis-cli> MH.LSH.CREATE index BANDS b ROWS r`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashLshCreate_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 6 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let mut bands = None;
+        let mut rows = None;
+        for i in (2..argc).step_by(2) {
+            let name = arg_bytes(*argv.add(i as usize));
+            let value = arg_long(*argv.add(i as usize + 1));
+            if name.eq_ignore_ascii_case(b"BANDS") {
+                bands = value;
+            } else if name.eq_ignore_ascii_case(b"ROWS") {
+                rows = value;
+            } else {
+                return reply_error(ctx, "ERR syntax error\0");
+            }
+        }
+
+        let index = match (bands, rows) {
+            (Some(bands), Some(rows)) if bands > 0 && rows > 0 => LshIndex::new(bands as usize, rows as usize),
+            _ => None,
+        };
+        let index = match index {
+            None => return reply_error(ctx, "ERR invalid BANDS or ROWS\0"),
+            Some(index) => index,
+        };
+
+        let Key(key, key_type) = open_rw(ctx, *argv.add(1));
+        if key_type != REDISMODULE_KEYTYPE_EMPTY {
+            return reply_error(ctx, "BUSYKEY Target key name already exists.\0");
+        }
+        RedisModule_ModuleTypeSetValue(key, LSH_INDEX_TYPE, Box::into_raw(Box::new(index)) as *mut c_void);
+        RedisModule_ReplicateVerbatim(ctx);
+
+        reply_ok(ctx)
+    }
+}
+
+/// Index sketches into MinHash LSH index. Already indexed keys are re-indexed by current sketches.
+/// Replies the number of newly indexed keys.
+///
+/// `redis-cli> MH.LSH.INSERT index key [key ...]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashLshInsert_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 3 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let index = match open_index(ctx, *argv.add(1)) {
+            Err(reply) => return reply,
+            Ok(None) => return reply_error(ctx, "ERR no such index\0"),
+            Ok(Some(index)) => index,
+        };
+
+        // check all sketches before indexing so that the index is not partially updated on error.
+        // once the first sketch is indexed, the rest never fail since they are compatible with it.
+        let mut reprs: Vec<HyperMinHashRepr> = Vec::with_capacity(argc as usize - 2);
+        for i in 2..argc {
+            match read_repr(ctx, *argv.add(i as usize)) {
+                Err(reply) => return reply,
+                Ok(None) => return reply_error(ctx, "ERR no such key\0"),
+                Ok(Some(repr)) => {
+                    if let Some(Err(err)) = reprs.first().map(|first| first.compatible(&repr)) {
+                        return reply_incompatible(ctx, err);
+                    }
+                    reprs.push(repr);
+                },
+            }
+        }
+
+        let mut inserted = 0;
+        for (i, repr) in reprs.iter().enumerate() {
+            match index.insert(arg_bytes(*argv.add(i + 2)), &repr.sketch()) {
+                Err(err) => return reply_lsh_error(ctx, err),
+                Ok(true) => inserted += 1,
+                Ok(false) => {},
+            }
+        }
+        RedisModule_ReplicateVerbatim(ctx);
+
+        RedisModule_ReplyWithLongLong(ctx, inserted)
+    }
+}
+
+/// Find keys similar to the sketch using MinHash LSH index.
+/// Candidates are re-ranked by estimated Jaccard index against their sketches at the time of insertion, and replied
+/// as flat array of key and similarity pairs in descending order of similarity. The query key itself is excluded.
+/// If THRESHOLD is specified, candidates less similar than the threshold are excluded.
+///
+/// `redis-cli> MH.LSH.QUERY index key [THRESHOLD t]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashLshQuery_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 3 && argc != 5 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let threshold = if argc == 5 {
+            if !arg_bytes(*argv.add(3)).eq_ignore_ascii_case(b"THRESHOLD") {
+                return reply_error(ctx, "ERR syntax error\0");
+            }
+            match arg_double(*argv.add(4)) {
+                Some(threshold) => threshold,
+                None => return reply_error(ctx, "ERR threshold is not a valid float\0"),
+            }
+        } else {
+            f64::NEG_INFINITY
+        };
+
+        let index = match read_index(ctx, *argv.add(1)) {
+            Err(reply) => return reply,
+            Ok(None) => return RedisModule_ReplyWithArray(ctx, 0),
+            Ok(Some(index)) => index,
+        };
+        let query = match read_repr(ctx, *argv.add(2)) {
+            Err(reply) => return reply,
+            Ok(None) => return RedisModule_ReplyWithArray(ctx, 0),
+            Ok(Some(repr)) => repr.sketch(),
+        };
+
+        let candidates = match index.query(&query) {
+            Err(err) => return reply_lsh_error(ctx, err),
+            Ok(candidates) => candidates,
+        };
+
+        reply_ranked(ctx, index, *argv.add(2), &query, candidates, threshold, |combiner| combiner.similarity())
+    }
+}
+
+/// Find keys containing the sketch using MinHash LSH index as LSH Ensemble.
+/// Candidates are re-ranked by estimated containment |Q∩X| / |Q|, where Q is the query sketch and X is the candidate's
+/// sketch at the time of insertion, and ones less than the threshold are excluded. Replies flat array of key and containment pairs
+/// in descending order of containment. The query key itself is excluded.
+///
+/// `redis-cli> MH.CONTAINS.QUERY index key THRESHOLD t`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashContainsQuery_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 5 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        if !arg_bytes(*argv.add(3)).eq_ignore_ascii_case(b"THRESHOLD") {
+            return reply_error(ctx, "ERR syntax error\0");
+        }
+        let threshold = match arg_double(*argv.add(4)) {
+            Some(threshold) if threshold > 0.0 && threshold <= 1.0 => threshold,
+            _ => return reply_error(ctx, "ERR threshold must be greater than 0 and at most 1\0"),
+        };
+
+        let index = match read_index(ctx, *argv.add(1)) {
+            Err(reply) => return reply,
+            Ok(None) => return RedisModule_ReplyWithArray(ctx, 0),
+            Ok(Some(index)) => index,
+        };
+        let query = match read_repr(ctx, *argv.add(2)) {
+            Err(reply) => return reply,
+            Ok(None) => return RedisModule_ReplyWithArray(ctx, 0),
+            Ok(Some(repr)) => repr.sketch(),
+        };
+
+        let candidates = match index.query_containment(&query, threshold) {
+            Err(err) => return reply_lsh_error(ctx, err),
+            Ok(candidates) => candidates,
+        };
+
+        let cardinality = query.cardinality();
+        reply_ranked(ctx, index, *argv.add(2), &query, candidates, threshold, |combiner| {
+            combiner.intersection() / cardinality
+        })
+    }
+}
+
+/// Remove keys from MinHash LSH index. Replies the number of removed keys.
+///
+/// `redis-cli> MH.LSH.REMOVE index key [key ...]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashLshRemove_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc < 3 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let index = match open_index(ctx, *argv.add(1)) {
+            Err(reply) => return reply,
+            Ok(None) => return RedisModule_ReplyWithLongLong(ctx, 0),
+            Ok(Some(index)) => index,
+        };
+
+        let mut removed = 0;
+        for i in 2..argc {
+            if index.remove(arg_bytes(*argv.add(i as usize))) {
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            RedisModule_ReplicateVerbatim(ctx);
+        }
+
+        RedisModule_ReplyWithLongLong(ctx, removed)
+    }
+}
+
+/// Restore MinHash LSH index serialized by AOF rewrite.
+///
+/// `redis-cli> MH.LSH.RESTORE index payload [REPLACE]`
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn MinHashLshRestore_RedisCommand(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> c_int {
+
+    unsafe {
+        RedisModule_AutoMemory(ctx);
+
+        if argc != 3 && argc != 4 {
+            return RedisModule_WrongArity(ctx);
+        }
+
+        let replace = if argc == 4 {
+            if !arg_bytes(*argv.add(3)).eq_ignore_ascii_case(b"REPLACE") {
+                return reply_error(ctx, "ERR syntax error\0");
+            }
+            true
+        } else {
+            false
+        };
+
+        let index = match LshIndex::from_bytes(arg_bytes(*argv.add(2))) {
+            None => return reply_error(ctx, "ERR payload is not a valid LSH index\0"),
+            Some(index) => index,
+        };
+
+        let Key(key, key_type) = open_rw(ctx, *argv.add(1));
+        if key_type != REDISMODULE_KEYTYPE_EMPTY && !replace {
+            return reply_error(ctx, "BUSYKEY Target key name already exists.\0");
+        }
+        RedisModule_ModuleTypeSetValue(key, LSH_INDEX_TYPE, Box::into_raw(Box::new(index)) as *mut c_void);
+        RedisModule_ReplicateVerbatim(ctx);
+
+        reply_ok(ctx)
+    }
+}
+
+struct Key(*mut RedisModuleKey, c_int);
+
+/// Sketch read by commands which accept Redis built-in HyperLogLog as well.
+enum Source {
+    Sketch(HyperMinHashRepr),
+    /// Converted from HyperLogLog on read.
+    Hll(HyperMinHash<ArrayRegisters, HashAlgorithm>),
+}
+
+impl Source {
+    fn params(&self) -> Params {
+        match self {
+            Source::Sketch(repr) => repr.params(),
+            Source::Hll(sketch) => sketch.params(),
+        }
+    }
+
+    fn hash(&self) -> HashAlgorithm {
+        match self {
+            Source::Sketch(repr) => repr.hash(),
+            Source::Hll(sketch) => *sketch.hasher(),
+        }
+    }
+
+    /// Check that the source can be merged into the sketch of given parameters and hash function.
+    fn compatible(&self, params: &Params, hash: HashAlgorithm) -> Result<(), Error> {
+        if self.params() != *params {
+            Err(Error::ParamsMismatch)
+        } else if self.hash() != hash {
+            Err(Error::HashMismatch)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn merge_into<T : RegisterVector>(&self, target: &mut HyperMinHash<T, HashAlgorithm>) -> Result<(), Error> {
+        match self {
+            Source::Sketch(repr) => target.merge(&repr.sketch()),
+            Source::Hll(sketch) => target.merge(sketch),
+        }
+    }
+
+    /// Merge only pattern lengths regardless of hash function. See `HyperMinHash::merge_cardinality`.
+    fn merge_cardinality_into<T : RegisterVector>(&self, target: &mut HyperMinHash<T, HashAlgorithm>) -> Result<(), Error> {
+        match self {
+            Source::Sketch(repr) => target.merge_cardinality(&repr.sketch()),
+            Source::Hll(sketch) => target.merge_cardinality(sketch),
+        }
+    }
+}
+
+/// Reply format of MH.SIGNATURE.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SignatureFormat {
+    Int,
+    Hex,
+    Binary,
+}
+
+impl SignatureFormat {
+    fn from_name(name: &[u8]) -> Option<Self> {
+        if name.eq_ignore_ascii_case(b"int") {
+            Some(SignatureFormat::Int)
+        } else if name.eq_ignore_ascii_case(b"hex") {
+            Some(SignatureFormat::Hex)
+        } else if name.eq_ignore_ascii_case(b"binary") {
+            Some(SignatureFormat::Binary)
+        } else {
+            None
+        }
+    }
+}
+
+fn arg_bytes<'a>(string: *mut RedisModuleString) -> &'a [u8] {
+    let mut len: size_t = 0;
+    unsafe {
+        let ptr = RedisModule_StringPtrLen(string, &mut len);
+        from_raw_parts(ptr, len)
+    }
+}
+
+fn arg_long(string: *mut RedisModuleString) -> Option<i64> {
+    let mut value: c_longlong = 0;
+    unsafe {
+        if RedisModule_StringToLongLong(string, &mut value) == REDISMODULE_OK {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+/// Take trailing flag of a command which takes variable number of keys.
+/// Flag is recognized only if at least one key remains, and argc is decreased accordingly.
+fn take_flag(argv: *mut *mut RedisModuleString, argc: &mut c_int, name: &[u8]) -> bool {
+    if *argc < 3 {
+        return false;
+    }
+
+    unsafe {
+        if arg_bytes(*argv.add(*argc as usize - 1)).eq_ignore_ascii_case(name) {
+            *argc -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Take trailing `name value` option of a command which takes variable number of keys.
+/// Option is recognized only if at least one key remains, and argc is decreased accordingly.
+fn take_option(argv: *mut *mut RedisModuleString, argc: &mut c_int, name: &[u8]) -> Option<*mut RedisModuleString> {
+    if *argc < 4 {
+        return None;
+    }
+
+    unsafe {
+        if arg_bytes(*argv.add(*argc as usize - 2)).eq_ignore_ascii_case(name) {
+            *argc -= 2;
+            Some(*argv.add(*argc as usize + 1))
+        } else {
+            None
+        }
+    }
+}
+
+/// Report given argument positions as keys if Redis requests key positions of a command registered with `getkeys-api`.
+/// Such commands take options besides variable number of keys, which static key spec can't exclude.
+/// Returns true for the request, in which case the command has to return without executing.
+fn report_keys<I : IntoIterator<Item = c_int>>(ctx: *mut RedisModuleCtx, positions: I) -> bool {
+    unsafe {
+        if RedisModule_IsKeysPositionRequest(ctx) == 0 {
+            return false;
+        }
+        for i in positions {
+            RedisModule_KeyAtPos(ctx, i);
+        }
+    }
+
+    true
+}
+
+fn arg_double(string: *mut RedisModuleString) -> Option<f64> {
+    let mut value: c_double = 0.0;
+    unsafe {
+        if RedisModule_StringToDouble(string, &mut value) == REDISMODULE_OK {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parse `FORMAT name` option of MH.IMPORT and MH.EXPORT. Only `liveramp` is supported.
+/// If the option is invalid, replies error and returns its result as Err.
+fn arg_format(ctx: *mut RedisModuleCtx, name: *mut RedisModuleString, format: *mut RedisModuleString) -> Result<(), c_int> {
+    if !arg_bytes(name).eq_ignore_ascii_case(b"FORMAT") {
+        return Err(reply_error(ctx, "ERR syntax error\0"));
+    }
+    if !arg_bytes(format).eq_ignore_ascii_case(b"liveramp") {
+        return Err(reply_error(ctx, "ERR unknown format\0"));
+    }
+
+    Ok(())
+}
+
+/// Parse confidence level, which must be in (0, 1).
+fn arg_confidence(string: *mut RedisModuleString) -> Option<f64> {
+    arg_double(string).filter(|&value| value > 0.0 && value < 1.0)
+}
+
+/// Parse 128-bit hash given as 16-byte binary (big endian) or 32-char hex string.
+fn arg_hash(string: *mut RedisModuleString) -> Option<u128> {
+    let arg = arg_bytes(string);
+    match arg.len() {
+        16 => {
+            let mut buf = [0u8; 16];
+            buf.copy_from_slice(arg);
+            Some(u128::from_be_bytes(buf))
+        },
+        32 if arg.iter().all(u8::is_ascii_hexdigit) => {
+            u128::from_str_radix(std::str::from_utf8(arg).ok()?, 16).ok()
+        },
+        _ => None,
+    }
+}
+
+fn open_ro(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString) -> Key {
+    unsafe {
+        let ptr = RedisModule_OpenKey(ctx, string, REDISMODULE_READ);
+        let key_type = RedisModule_KeyType(ptr);
+
+        Key(ptr, key_type)
+    }
+}
+
+fn open_rw(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString) -> Key {
+    unsafe {
+        let ptr = RedisModule_OpenKey(ctx, string, REDISMODULE_READ | REDISMODULE_WRITE);
+        let key_type = RedisModule_KeyType(ptr);
+
+        Key(ptr, key_type)
+    }
+}
+
+/// Returns true if the key is a Redis built-in HyperLogLog.
+fn is_hll_key(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString) -> bool {
+    let Key(key, key_type) = open_ro(ctx, string);
+    key_type == REDISMODULE_KEYTYPE_STRING && is_hll(string_dma(key).as_slice())
+}
+
+fn is_sketch(key: *mut RedisModuleKey) -> bool {
+    unsafe {
+        RedisModule_ModuleTypeGetType(key) == HYPERMINHASH_TYPE
+    }
+}
+
+fn value_of<'a>(key: *mut RedisModuleKey) -> &'a mut SketchValue {
+    unsafe {
+        &mut *(RedisModule_ModuleTypeGetValue(key) as *mut SketchValue)
+    }
+}
+
+fn set_value<'a>(key: *mut RedisModuleKey, value: SketchValue) -> &'a mut SketchValue {
+    let ptr = Box::into_raw(Box::new(value));
+    unsafe {
+        RedisModule_ModuleTypeSetValue(key, HYPERMINHASH_TYPE, ptr as *mut c_void);
+        &mut *ptr
+    }
+}
+
+/// Set an empty sketch to the key which is opened for write.
+fn create_value<'a>(key: *mut RedisModuleKey, params: &Params, hash: HashAlgorithm) -> &'a mut SketchValue {
+    let mut value = SketchValue::new(HyperMinHashRepr::sparse_len(0));
+    HyperMinHashRepr::initialize(&mut value.bytes(), params, &hash);
+
+    set_value(key, value)
+}
+
+/// Open the key for read.
+/// Returns None if the key doesn't exist.
+/// If the key is not a sketch, replies error and returns its result as Err.
+fn read_repr(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString) -> Result<Option<HyperMinHashRepr>, c_int> {
+    let Key(key, key_type) = open_ro(ctx, string);
+
+    let bytes = match key_type {
+        REDISMODULE_KEYTYPE_EMPTY => return Ok(None),
+        REDISMODULE_KEYTYPE_MODULE if is_sketch(key) => value_of(key).bytes(),
+        // sketch created by older version
+        REDISMODULE_KEYTYPE_STRING => string_dma(key),
+        _ => return Err(reply_wrong_type(ctx)),
+    };
+
+    let hll = is_hll(bytes.as_slice());
+    match HyperMinHashRepr::parse(bytes) {
+        None if hll => Err(reply_hll_unsupported(ctx)),
+        None => Err(reply_wrong_type(ctx)),
+        Some(repr) => Ok(Some(repr)),
+    }
+}
+
+/// Open the key for read, accepting Redis built-in HyperLogLog as well as sketches.
+/// HyperLogLog is converted into a sketch, which supports only cardinality estimation.
+/// Returns None if the key doesn't exist.
+/// If the key is neither a sketch nor a HyperLogLog, replies error and returns its result as Err.
+fn read_source(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString) -> Result<Option<Source>, c_int> {
+    let Key(key, key_type) = open_ro(ctx, string);
+
+    if key_type == REDISMODULE_KEYTYPE_STRING {
+        let bytes = string_dma(key);
+        if is_hll(bytes.as_slice()) {
+            return match HyperMinHash::from_hll(bytes.as_slice()) {
+                None => Err(reply_error(ctx, "WRONGTYPE Key is not a valid HyperLogLog string value.\0")),
+                Some(sketch) => Ok(Some(Source::Hll(sketch))),
+            };
+        }
+    }
+
+    read_repr(ctx, string).map(|repr| repr.map(Source::Sketch))
+}
+
+/// Open the LSH index for read.
+/// Returns None if the key doesn't exist.
+/// If the key is not an LSH index, replies error and returns its result as Err.
+fn read_index<'a>(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString) -> Result<Option<&'a LshIndex>, c_int> {
+    let Key(key, key_type) = open_ro(ctx, string);
+    index_of(ctx, key, key_type).map(|index| index.map(|index| &*index))
+}
+
+/// Open the LSH index for write.
+/// Returns None if the key doesn't exist.
+/// If the key is not an LSH index, replies error and returns its result as Err.
+fn open_index<'a>(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString) -> Result<Option<&'a mut LshIndex>, c_int> {
+    let Key(key, key_type) = open_rw(ctx, string);
+    index_of(ctx, key, key_type)
+}
+
+fn index_of<'a>(ctx: *mut RedisModuleCtx, key: *mut RedisModuleKey, key_type: c_int) -> Result<Option<&'a mut LshIndex>, c_int> {
+    unsafe {
+        match key_type {
+            REDISMODULE_KEYTYPE_EMPTY => Ok(None),
+            REDISMODULE_KEYTYPE_MODULE if RedisModule_ModuleTypeGetType(key) == LSH_INDEX_TYPE =>
+                Ok(Some(&mut *(RedisModule_ModuleTypeGetValue(key) as *mut LshIndex))),
+            _ => Err(RedisModule_ReplyWithError(ctx, "WRONGTYPE Key is not a valid HyperMinHash LSH index.\0".as_ptr())),
+        }
+    }
+}
+
+/// Open the key for write.
+/// Returns None as the value if the key doesn't exist.
+/// Sketch created by older version, which is stored as plain string, is converted into module type value.
+/// If the key is not a sketch, replies error and returns its result as Err.
+fn open_value<'a>(
+    ctx: *mut RedisModuleCtx,
+    string: *mut RedisModuleString) -> Result<(*mut RedisModuleKey, Option<&'a mut SketchValue>), c_int> {
+
+    let Key(key, key_type) = open_rw(ctx, string);
+
+    match key_type {
+        REDISMODULE_KEYTYPE_EMPTY => Ok((key, None)),
+        REDISMODULE_KEYTYPE_MODULE if is_sketch(key) => Ok((key, Some(value_of(key)))),
+        REDISMODULE_KEYTYPE_STRING => {
+            match SketchValue::from_bytes(string_dma(key).as_slice()) {
+                None => Err(reply_wrong_type(ctx)),
+                Some(value) => Ok((key, Some(set_value(key, value)))),
+            }
+        },
+        _ => Err(reply_wrong_type(ctx)),
+    }
+}
+
+/// Combine sketches of argv[1..argc] for similarity estimation.
+/// Non-existent keys are ignored.
+/// If any key is not a sketch or sketches are incompatible, replies error and returns its result as Err.
+fn combine_keys(
+    ctx: *mut RedisModuleCtx,
+    argv: *mut *mut RedisModuleString,
+    argc: c_int) -> Result<MinHashCombiner, c_int> {
+
+    let mut combiner = MinHashCombiner::new();
+    for i in 1..argc {
+        match read_repr(ctx, unsafe { *argv.add(i as usize) }) {
+            Err(reply) => return Err(reply),
+            Ok(None) => continue,
+            Ok(Some(repr)) => {
+                if let Err(err) = combiner.combine(&repr.sketch()) {
+                    return Err(reply_incompatible(ctx, err));
+                }
+            },
+        }
+    }
+
+    Ok(combiner)
+}
+
+/// Update the sketch by given function, which returns true if any register is updated.
+/// Key is initialized with default parameters if it doesn't exist.
+/// Replies 1 if any register is updated, otherwise 0.
+fn add_to_sketch<F>(ctx: *mut RedisModuleCtx, string: *mut RedisModuleString, add: F) -> c_int
+    where F: FnOnce(&mut HyperMinHash<Registers, HashAlgorithm>) -> bool {
+
+    let value = match open_value(ctx, string) {
+        Err(reply) => return reply,
+        Ok((_, Some(value))) => value,
+        Ok((key, None)) => create_value(key, &Params::default(), HashAlgorithm::default()),
+    };
+
+    unsafe {
+        match HyperMinHashRepr::parse(value.bytes()) {
+            None => reply_wrong_type(ctx),
+            Some(mut repr) => {
+                let mut sketch = repr.sketch();
+                let updated = add(&mut sketch);
+                if updated {
+                    repr.invalidate_cache();
+                    // dense registers are updated in place
+                    if let Registers::Sparse(registers) = &sketch.registers {
+                        store_sparse(value, &sketch.params(), registers);
+                    }
+                    RedisModule_ReplicateVerbatim(ctx);
+                }
+
+                RedisModule_ReplyWithLongLong(ctx, if updated { 1 } else { 0 })
+            },
+        }
+    }
+}
+
+/// Store sparse registers into the value.
+/// Registers are promoted to dense representation if they no longer fit in sparse representation.
+fn store_sparse(value: &mut SketchValue, params: &Params, registers: &SparseVector) {
+    if HyperMinHashRepr::sparse_fits(params, registers.len()) {
+        value.resize(HyperMinHashRepr::sparse_len(registers.len()));
+        HyperMinHashRepr::write_sparse(&mut value.bytes(), registers);
+    } else {
+        value.resize(HyperMinHashRepr::dense_len(params));
+        HyperMinHashRepr::write_dense(&mut value.bytes(), params, registers);
+    }
+}
+
+fn string_dma(key: *mut RedisModuleKey) -> CByteArray {
+    let mut len: size_t = 0;
+    unsafe {
+        let ptr = RedisModule_StringDMA(key, &mut len, REDISMODULE_READ);
+        CByteArray::wrap(ptr, len)
+    }
+}
+
+fn reply_hll_unsupported(ctx: *mut RedisModuleCtx) -> c_int {
+    reply_error(ctx, "WRONGTYPE Key is a HyperLogLog, which has no MinHash bits. Only MH.COUNT and MH.MERGE accept it.\0")
+}
+
+fn reply_wrong_type(ctx: *mut RedisModuleCtx) -> c_int {
     unsafe {
         RedisModule_ReplyWithError(
-            ctx, "WRONGTYPE Key is not a valid HyperMinHash string value.\0".as_ptr())
+            ctx, "WRONGTYPE Key is not a valid HyperMinHash value.\0".as_ptr())
+    }
+}
+
+/// Re-rank LSH candidates by the score of each candidate combined with the query,
+/// and reply candidates whose score is at least the threshold as flat array of key and score pairs in descending order.
+/// Candidates are scored by their sketches held in the index, so that no undeclared key is accessed.
+/// The query key itself, and candidates which are not compatible are excluded.
+fn reply_ranked<F>(
+    ctx: *mut RedisModuleCtx,
+    index: &LshIndex,
+    query_key: *mut RedisModuleString,
+    query: &HyperMinHash<Registers, HashAlgorithm>,
+    candidates: Vec<&[u8]>,
+    threshold: f64,
+    score: F) -> c_int
+    where F : Fn(&MinHashCombiner) -> f64 {
+
+    let query_key = arg_bytes(query_key);
+    let mut result = Vec::with_capacity(candidates.len());
+    for candidate in candidates {
+        if candidate == query_key {
+            continue;
+        }
+
+        let sketch = match index.indexed_sketch(candidate) {
+            None => continue,
+            Some(sketch) => sketch,
+        };
+
+        let mut combiner = MinHashCombiner::new();
+        if let Err(err) = combiner.combine(query) {
+            return reply_incompatible(ctx, err);
+        }
+        if combiner.combine(&sketch).is_err() {
+            continue;
+        }
+        let score = score(&combiner);
+        if score >= threshold {
+            result.push((candidate, score));
+        }
+    }
+    result.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    unsafe {
+        RedisModule_ReplyWithArray(ctx, result.len() as c_long * 2);
+        for (candidate, score) in result {
+            RedisModule_ReplyWithStringBuffer(ctx, candidate.as_ptr(), candidate.len());
+            RedisModule_ReplyWithDouble(ctx, score as c_double);
+        }
+    }
+    REDISMODULE_OK
+}
+
+/// Reply cardinality, or its confidence interval if confidence level is given.
+fn reply_cardinality(ctx: *mut RedisModuleCtx, cardinality: f64, params: &Params, confidence: Option<f64>) -> c_int {
+    match confidence {
+        None => unsafe { RedisModule_ReplyWithLongLong(ctx, cardinality as c_longlong) },
+        Some(level) => reply_interval(ctx, &ConfidenceInterval::cardinality(cardinality, params, level)),
+    }
+}
+
+/// Reply confidence interval of a cardinality as an array of rounded [estimate, lower, upper].
+fn reply_interval(ctx: *mut RedisModuleCtx, interval: &ConfidenceInterval) -> c_int {
+    unsafe {
+        RedisModule_ReplyWithArray(ctx, 3);
+        RedisModule_ReplyWithLongLong(ctx, interval.estimate.round() as c_longlong);
+        RedisModule_ReplyWithLongLong(ctx, interval.lower.round() as c_longlong);
+        RedisModule_ReplyWithLongLong(ctx, interval.upper.round() as c_longlong)
+    }
+}
+
+fn reply_invalid_confidence(ctx: *mut RedisModuleCtx) -> c_int {
+    reply_error(ctx, "ERR confidence level must be between 0 and 1 exclusive\0")
+}
+
+fn reply_incompatible(ctx: *mut RedisModuleCtx, err: Error) -> c_int {
+    match err {
+        Error::ParamsMismatch =>
+            reply_error(ctx, "ERR sketches have different HyperMinHash parameters\0"),
+        Error::HashMismatch =>
+            reply_error(ctx, "ERR sketches are built with different hash functions\0"),
+        Error::NoMinHash =>
+            reply_error(ctx, "ERR sketch converted from HyperLogLog supports only cardinality estimation\0"),
+    }
+}
+
+/// Returns true if either hash function is the one of Redis HyperLogLog.
+fn is_hll_hash(a: HashAlgorithm, b: HashAlgorithm) -> bool {
+    !a.has_minhash() || !b.has_minhash()
+}
+
+/// HyperLogLog sources fail to merge into sketches built by MH.ADD because of hash functions, which deserves specific message.
+fn reply_merge_incompatible(ctx: *mut RedisModuleCtx, err: Error, sources: &[Source], hash: HashAlgorithm) -> c_int {
+    if err == Error::HashMismatch && sources.iter().any(|source| is_hll_hash(source.hash(), hash)) {
+        reply_error(ctx, "ERR HyperLogLog hashes elements differently, so it can be merged only with sketches converted from HyperLogLog. Use MH.COUNT with APPROX for approximate union cardinality\0")
+    } else {
+        reply_incompatible(ctx, err)
+    }
+}
+
+fn reply_lsh_error(ctx: *mut RedisModuleCtx, err: LshError) -> c_int {
+    match err {
+        LshError::Incompatible(err) => reply_incompatible(ctx, err),
+        LshError::TooFewRegisters => reply_error(ctx, "ERR sketch has fewer registers than BANDS * ROWS\0"),
+    }
+}
+
+fn reply_error(ctx: *mut RedisModuleCtx, err: &str) -> c_int {
+    unsafe {
+        RedisModule_ReplyWithError(ctx, err.as_ptr())
     }
 }
 
@@ -350,3 +2143,78 @@ fn reply_ok(ctx: *mut RedisModuleCtx) -> c_int {
         RedisModule_ReplyWithSimpleString(ctx, "OK\0".as_ptr())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_estimates() {
+        let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in 0..10000 {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+        let mut combiner = MinHashCombiner::new();
+        combiner.combine(&sketch).unwrap();
+        combiner.combine(&sketch).unwrap();
+
+        // MH.STATS a a
+        let estimates = stats_estimates(&combiner, &[true, true]);
+        assert_eq!(estimates, combiner.estimates());
+        assert!(estimates.similarity > 0.99);
+
+        // MH.STATS a missing a
+        let mut combiner = MinHashCombiner::new();
+        combiner.combine(&sketch).unwrap();
+        combiner.combine(&sketch).unwrap();
+        let estimates = stats_estimates(&combiner, &[true, false, true]);
+        assert_eq!(estimates.cardinalities, vec![sketch.cardinality(), 0.0, sketch.cardinality()]);
+        assert_eq!(estimates.union, combiner.estimates().union);
+        assert_eq!(estimates.similarity, 0.0);
+        assert_eq!(estimates.intersection, 0.0);
+        assert_eq!(estimates.containments, vec![0.0, 0.0, 0.0]);
+        assert_eq!(estimates.dice, 0.0);
+        assert_eq!(estimates.overlap, 0.0);
+
+        // MH.STATS missing
+        let estimates = stats_estimates(&MinHashCombiner::new(), &[false]);
+        assert_eq!(estimates.cardinalities, vec![0.0]);
+        assert_eq!(estimates.union, 0.0);
+        assert_eq!(estimates.intersection, 0.0);
+    }
+
+    #[test]
+    fn test_union_sources() {
+        // PFADD hll id0 .. id9999
+        let mut hll = HyperMinHash::with_hasher(new_array_registers(&Params::default()), Params::default(), HashAlgorithm::RedisHll);
+        // MH.ADD key id5000 .. id14999
+        let mut sketch = HyperMinHash::wrap(new_array_registers(&Params::default()));
+        for i in 0..10000 {
+            hll.add(format!("id{}", i).as_bytes());
+            sketch.add(format!("id{}", i + 5000).as_bytes());
+        }
+        let mut bytes = sketch.to_bytes();
+        let (ptr, len) = (bytes.as_mut_ptr(), bytes.len());
+        let repr = || HyperMinHashRepr::parse(CByteArray::wrap(ptr, len)).unwrap();
+
+        let mut sources = vec![Source::Hll(hll), Source::Sketch(repr())];
+        assert_eq!(union_sources(&sources, false).err(), Some(Error::HashMismatch));
+
+        // overlapping 5000 elements are counted twice. i.e. 20000 rather than 15000
+        let union = union_sources(&sources, true).unwrap().unwrap();
+        assert!((union.cardinality() - 20000.0).abs() < 20000.0 * 0.02);
+        assert_eq!(union.hasher(), &HashAlgorithm::RedisHll);
+
+        // regardless of the order of keys
+        sources.reverse();
+        let reversed = union_sources(&sources, true).unwrap().unwrap();
+        assert_eq!(reversed.registers, union.registers);
+
+        // sources built with the same hash function are unioned exactly even with APPROX
+        let union = union_sources(&[Source::Sketch(repr()), Source::Sketch(repr())], true).unwrap().unwrap();
+        assert_eq!(union.hasher(), &HashAlgorithm::Murmur3);
+        assert_eq!(union.cardinality(), sketch.cardinality());
+
+        assert!(union_sources(&[], true).unwrap().is_none());
+    }
+}
@@ -0,0 +1,159 @@
+//! HyperMinHash Redis module data type.
+//!
+//! Sketches are stored as a module type value which holds `HyperMinHashRepr` bytes,
+//! so that plain string commands (e.g. `APPEND`, `SETRANGE`) can't corrupt them.
+//!
+//! Sketches created by older versions are stored as plain strings.
+//! They are converted into module type value on first write access.
+
+use super::*;
+use super::dump;
+use crate::hyperminhash::dma::CByteArray;
+use crate::hyperminhash::repr::HyperMinHashRepr;
+use libc::{c_int, c_void, size_t};
+use std::mem::size_of;
+use std::ptr::null_mut;
+use std::slice::from_raw_parts;
+
+pub const TYPE_NAME: &str = "HyMinHash";
+pub const ENCODING_VERSION: c_int = 0;
+
+pub static mut HYPERMINHASH_TYPE: *mut RedisModuleType = null_mut();
+
+/// Module type value holding HyperMinHashRepr bytes.
+#[derive(Clone)]
+pub struct SketchValue {
+    data: Vec<u8>,
+}
+
+impl SketchValue {
+    pub fn new(len: usize) -> Self {
+        Self { data: vec![0u8; len], }
+    }
+
+    /// Wrap bytes which are known to be a valid HyperMinHashRepr.
+    pub fn wrap(data: Vec<u8>) -> Self {
+        Self { data, }
+    }
+
+    /// Returns None if given bytes are not a valid HyperMinHashRepr.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut value = Self { data: bytes.to_vec(), };
+        HyperMinHashRepr::parse(value.bytes())?;
+
+        Some(value)
+    }
+
+    /// Raw view of underlying bytes.
+    /// The view is invalidated once the value is resized.
+    pub fn bytes(&mut self) -> CByteArray {
+        CByteArray::wrap(self.data.as_mut_ptr(), self.data.len())
+    }
+
+    pub fn resize(&mut self, len: usize) {
+        self.data.resize(len, 0);
+    }
+
+    /// Pin the cardinality estimate until registers are updated. See `HyperMinHashRepr::set_estimate`.
+    pub fn set_estimate(&mut self, cardinality: u64) {
+        // never fails since the value always holds a valid HyperMinHashRepr
+        HyperMinHashRepr::parse(self.bytes()).unwrap().set_estimate(cardinality);
+    }
+}
+
+pub fn type_methods() -> RedisModuleTypeMethods {
+    RedisModuleTypeMethods {
+        version: REDISMODULE_TYPE_METHOD_VERSION,
+        rdb_load: Some(rdb_load),
+        rdb_save: Some(rdb_save),
+        aof_rewrite: Some(aof_rewrite),
+        mem_usage: Some(mem_usage),
+        digest: Some(digest),
+        free: Some(free),
+        aux_load: None,
+        aux_save: None,
+        aux_save_triggers: 0,
+        free_effort: None,
+        unlink: None,
+        copy: Some(copy),
+        defrag: None,
+    }
+}
+
+extern "C" fn rdb_load(rdb: *mut RedisModuleIO, encver: c_int) -> *mut c_void {
+    if encver != ENCODING_VERSION {
+        return null_mut();
+    }
+
+    unsafe {
+        let mut len: size_t = 0;
+        let ptr = RedisModule_LoadStringBuffer(rdb, &mut len);
+        if ptr.is_null() {
+            return null_mut();
+        }
+        let value = SketchValue::from_bytes(from_raw_parts(ptr, len));
+        RedisModule_Free(ptr as *mut c_void);
+
+        match value {
+            None => null_mut(),
+            Some(value) => Box::into_raw(Box::new(value)) as *mut c_void,
+        }
+    }
+}
+
+extern "C" fn rdb_save(rdb: *mut RedisModuleIO, value: *mut c_void) {
+    unsafe {
+        let value = &*(value as *const SketchValue);
+        RedisModule_SaveStringBuffer(rdb, value.data.as_ptr(), value.data.len());
+    }
+}
+
+/// Sketch is rewritten as MH.RESTORE command with portable dump.
+extern "C" fn aof_rewrite(aof: *mut RedisModuleIO, key: *mut RedisModuleString, value: *mut c_void) {
+    unsafe {
+        let value = &mut *(value as *mut SketchValue);
+        if let Some(repr) = HyperMinHashRepr::parse(value.bytes()) {
+            let blob = dump::dump(&repr);
+            RedisModule_EmitAOF(
+                aof,
+                "MH.RESTORE\0".as_ptr(),
+                "sbc\0".as_ptr(),
+                key,
+                blob.as_ptr(),
+                blob.len(),
+                "REPLACE\0".as_ptr());
+        }
+    }
+}
+
+extern "C" fn mem_usage(value: *const c_void) -> size_t {
+    unsafe {
+        let value = &*(value as *const SketchValue);
+        size_of::<SketchValue>() + value.data.capacity()
+    }
+}
+
+extern "C" fn digest(md: *mut RedisModuleDigest, value: *mut c_void) {
+    unsafe {
+        let value = &*(value as *const SketchValue);
+        RedisModule_DigestAddStringBuffer(md, value.data.as_ptr(), value.data.len());
+        RedisModule_DigestEndSequence(md);
+    }
+}
+
+extern "C" fn free(value: *mut c_void) {
+    unsafe {
+        drop(Box::from_raw(value as *mut SketchValue));
+    }
+}
+
+extern "C" fn copy(
+    _fromkey: *mut RedisModuleString,
+    _tokey: *mut RedisModuleString,
+    value: *const c_void) -> *mut c_void {
+
+    unsafe {
+        let value = &*(value as *const SketchValue);
+        Box::into_raw(Box::new(value.clone())) as *mut c_void
+    }
+}
@@ -0,0 +1,347 @@
+//! Portable serialization format used by MH.DUMP / MH.RESTORE.
+//!
+//! Unlike `HyperMinHashRepr`, which is the in-memory layout of a key,
+//! this format is versioned and self-describing so that sketches can be moved
+//! across deployments or processed by offline jobs.
+//!
+//! All integers are encoded in little endian.
+//!
+//! ```text
+//!  +------+---+---+---+---+---+---+----------+------+-----+---------+-------+
+//!  | MHDP | V | E | P | Q | R | H | Estimate | Seed | Len | Payload | CRC32 |
+//!  +------+---+---+---+---+---+---+----------+------+-----+---------+-------+
+//! ```
+//!
+//! - MHDP: 4 byte magic string.
+//! - V: 1 byte format version.
+//! - E: 1 byte register encoding of the payload. (DENSE or SPARSE)
+//! - P, Q, R: 1 byte each HyperMinHash parameters.
+//! - H: 1 byte identifier of the hash function.
+//! - Estimate: 8 byte cardinality estimate pinned on creation (e.g. by MH.INTERSECTSTORE), or all ones if none.
+//! - Seed: 8 byte hash seed which was used to add elements.
+//! - Len: 4 byte length of the payload.
+//! - Payload: registers in the encoding.
+//!   - DENSE: 2^P 16-bit register values.
+//!   - SPARSE: (16-bit register index, 16-bit register value) pairs of non-empty registers sorted by index.
+//! - CRC32: 4 byte CRC-32 (IEEE) checksum of all preceding bytes.
+
+use super::datatype::SketchValue;
+use crate::hyperminhash::dma::CByteArray;
+use crate::hyperminhash::hash::{HashAlgorithm, SketchHasher};
+use crate::hyperminhash::repr::{HyperMinHashRepr, Registers};
+use crate::hyperminhash::sparse::SparseVector;
+use crate::hyperminhash::{new_array_registers, Params, RegisterVector};
+use crate::hyperminhash::sketch::HASH_SEED;
+
+const MAGIC: [u8; 4] = [b'M',b'H',b'D',b'P'];
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 30;
+const NO_ESTIMATE: u64 = u64::MAX;
+const CHECKSUM_LEN: usize = 4;
+
+const ENCODING_DENSE: u8 = 0;
+const ENCODING_SPARSE: u8 = 1;
+
+/// Errors which can occur while restoring a dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreError {
+    /// Payload is truncated or not a HyperMinHash dump.
+    Malformed,
+    /// Dump was created by newer version.
+    UnsupportedVersion,
+    /// Checksum doesn't match.
+    ChecksumMismatch,
+    /// Sketch was built with unknown hash function.
+    UnsupportedHash,
+    /// Sketch was built with different hash seed.
+    HashSeedMismatch,
+    /// Parameters are out of supported range.
+    InvalidParams,
+    /// Registers are broken.
+    InvalidRegisters,
+}
+
+impl RestoreError {
+    /// Error reply for the error. (null-terminated)
+    pub fn message(&self) -> &'static str {
+        match self {
+            RestoreError::Malformed => "ERR DUMP payload is malformed\0",
+            RestoreError::UnsupportedVersion => "ERR DUMP payload version is not supported\0",
+            RestoreError::ChecksumMismatch => "ERR DUMP payload checksum is wrong\0",
+            RestoreError::UnsupportedHash => "ERR DUMP payload hash function is not supported\0",
+            RestoreError::HashSeedMismatch => "ERR DUMP payload hash seed is not supported\0",
+            RestoreError::InvalidParams => "ERR DUMP payload has invalid HyperMinHash parameters\0",
+            RestoreError::InvalidRegisters => "ERR DUMP payload has invalid registers\0",
+        }
+    }
+}
+
+/// Serialize the sketch into portable format.
+pub fn dump(repr: &HyperMinHashRepr) -> Vec<u8> {
+    let params = repr.params();
+
+    let (encoding, payload) = match repr.registers() {
+        Registers::Dense(registers) => {
+            let mut payload = Vec::with_capacity(params.num_registers() * 2);
+            for i in 0..params.num_registers() {
+                payload.extend_from_slice(&(registers.register_at(i) as u16).to_le_bytes());
+            }
+            (ENCODING_DENSE, payload)
+        },
+        Registers::Sparse(registers) => {
+            let mut payload = vec![0u8; SparseVector::sparse_bytes(registers.len())];
+            registers.write(&mut CByteArray::wrap(payload.as_mut_ptr(), payload.len()));
+            (ENCODING_SPARSE, payload)
+        },
+    };
+
+    let mut result = Vec::with_capacity(HEADER_LEN + payload.len() + CHECKSUM_LEN);
+    result.extend_from_slice(&MAGIC);
+    result.push(VERSION);
+    result.push(encoding);
+    result.push(params.p() as u8);
+    result.push(params.q() as u8);
+    result.push(params.r() as u8);
+    result.push(repr.hash().id());
+    let estimate = if repr.estimated() { repr.get_cache() } else { NO_ESTIMATE };
+    result.extend_from_slice(&estimate.to_le_bytes());
+    result.extend_from_slice(&HASH_SEED.to_le_bytes());
+    result.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    result.extend_from_slice(&payload);
+
+    let checksum = crc32(&result);
+    result.extend_from_slice(&checksum.to_le_bytes());
+
+    result
+}
+
+/// Deserialize the dump into a module type value.
+/// Everything is validated before the value is built.
+pub fn restore(blob: &[u8]) -> Result<SketchValue, RestoreError> {
+    if blob.len() <= MAGIC.len() || blob[0..4] != MAGIC {
+        return Err(RestoreError::Malformed);
+    }
+    if blob[4] != VERSION {
+        return Err(RestoreError::UnsupportedVersion);
+    }
+    if blob.len() < HEADER_LEN + CHECKSUM_LEN {
+        return Err(RestoreError::Malformed);
+    }
+
+    let (body, checksum) = blob.split_at(blob.len() - CHECKSUM_LEN);
+    if crc32(body) != read_u32(checksum) {
+        return Err(RestoreError::ChecksumMismatch);
+    }
+
+    let params = Params::new(blob[6] as usize, blob[7] as usize, blob[8] as usize)
+        .ok_or(RestoreError::InvalidParams)?;
+
+    let hash = HashAlgorithm::from_id(blob[9]).ok_or(RestoreError::UnsupportedHash)?;
+
+    let estimate = Some(read_u64(&blob[10..18])).filter(|&estimate| estimate != NO_ESTIMATE);
+
+    if read_u64(&blob[18..26]) != HASH_SEED {
+        return Err(RestoreError::HashSeedMismatch);
+    }
+
+    let payload_len = read_u32(&blob[26..HEADER_LEN]) as usize;
+    if payload_len != body.len() - HEADER_LEN {
+        return Err(RestoreError::Malformed);
+    }
+    let mut payload = body[HEADER_LEN..].to_vec();
+    let payload = CByteArray::wrap(payload.as_mut_ptr(), payload.len());
+
+    match blob[5] {
+        ENCODING_DENSE => {
+            if payload_len != params.num_registers() * 2 {
+                return Err(RestoreError::Malformed);
+            }
+            let mut registers = new_array_registers(&params);
+            for (i, register) in registers.iter_mut().enumerate() {
+                *register = u32::from(payload[2 * i]) | u32::from(payload[2 * i + 1]) << 8;
+            }
+            validate_registers(&registers, &params)?;
+
+            Ok(to_value(&params, hash, &registers, estimate))
+        },
+        ENCODING_SPARSE => {
+            if !SparseVector::validate(&payload, &params) {
+                return Err(RestoreError::InvalidRegisters);
+            }
+            let registers = SparseVector::read(&payload);
+            validate_registers(&registers, &params)?;
+
+            Ok(to_value(&params, hash, &registers, estimate))
+        },
+        _ => Err(RestoreError::Malformed),
+    }
+}
+
+fn to_value<T : RegisterVector>(params: &Params, hash: HashAlgorithm, registers: &T, estimate: Option<u64>) -> SketchValue {
+    let mut value = SketchValue::wrap(HyperMinHashRepr::to_vec(params, &hash, registers));
+    if let Some(estimate) = estimate {
+        value.set_estimate(estimate);
+    }
+
+    value
+}
+
+/// Check that every register value can be produced by given parameters.
+fn validate_registers<T : RegisterVector>(registers: &T, params: &Params) -> Result<(), RestoreError> {
+    for i in 0..params.num_registers() {
+        if (registers.register_at(i) as usize >> params.r()) >= params.histogram_len() {
+            return Err(RestoreError::InvalidRegisters);
+        }
+    }
+
+    Ok(())
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[0..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[0..8]);
+    u64::from_le_bytes(buf)
+}
+
+/// CRC-32 (IEEE 802.3, reflected polynomial 0xedb88320)
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyperminhash::hash::Xxh3;
+    use crate::hyperminhash::sketch::HyperMinHash;
+
+    fn new_value(params: &Params, hash: HashAlgorithm, n: usize) -> SketchValue {
+        let mut sketch = HyperMinHash::with_hasher(new_array_registers(params), *params, hash);
+        for i in 0..n {
+            sketch.add(format!("id{}", i).as_bytes());
+        }
+
+        SketchValue::wrap(sketch.to_bytes())
+    }
+
+    fn assert_same_registers(a: &mut SketchValue, b: &mut SketchValue) {
+        let a = HyperMinHashRepr::parse(a.bytes()).unwrap();
+        let b = HyperMinHashRepr::parse(b.bytes()).unwrap();
+        assert_eq!(a.params(), b.params());
+
+        let num_registers = a.params().num_registers();
+        let (a, b) = (a.registers(), b.registers());
+        for i in 0..num_registers {
+            assert_eq!(a.register_at(i), b.register_at(i));
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_round_trip_sparse() {
+        let mut value = new_value(&Params::default(), HashAlgorithm::Murmur3, 100);
+        let blob = dump(&HyperMinHashRepr::parse(value.bytes()).unwrap());
+        assert_eq!(blob[5], ENCODING_SPARSE);
+
+        let mut restored = restore(&blob).unwrap();
+        assert!(matches!(
+            HyperMinHashRepr::parse(restored.bytes()).unwrap().registers(), Registers::Sparse(_)));
+        assert_same_registers(&mut value, &mut restored);
+    }
+
+    #[test]
+    fn test_round_trip_dense() {
+        let mut value = new_value(&Params::default(), HashAlgorithm::Murmur3, 10000);
+        let blob = dump(&HyperMinHashRepr::parse(value.bytes()).unwrap());
+        assert_eq!(blob[5], ENCODING_DENSE);
+        assert_eq!(blob.len(), HEADER_LEN + Params::default().num_registers() * 2 + CHECKSUM_LEN);
+
+        let mut restored = restore(&blob).unwrap();
+        assert!(matches!(
+            HyperMinHashRepr::parse(restored.bytes()).unwrap().registers(), Registers::Dense(_)));
+        assert_same_registers(&mut value, &mut restored);
+    }
+
+    #[test]
+    fn test_round_trip_params() {
+        let params = Params::new(10, 4, 12).unwrap();
+        let mut value = new_value(&params, HashAlgorithm::Xxh3, 100);
+        let blob = dump(&HyperMinHashRepr::parse(value.bytes()).unwrap());
+        assert_eq!(&blob[6..10], &[10, 4, 12, Xxh3::ID]);
+
+        let mut restored = restore(&blob).unwrap();
+        let repr = HyperMinHashRepr::parse(restored.bytes()).unwrap();
+        assert_eq!(repr.params(), params);
+        assert_eq!(repr.hash(), HashAlgorithm::Xxh3);
+    }
+
+    #[test]
+    fn test_round_trip_estimate() {
+        let mut value = new_value(&Params::default(), HashAlgorithm::Murmur3, 100);
+        let blob = dump(&HyperMinHashRepr::parse(value.bytes()).unwrap());
+        assert_eq!(&blob[10..18], &NO_ESTIMATE.to_le_bytes());
+        assert!(!HyperMinHashRepr::parse(restore(&blob).unwrap().bytes()).unwrap().estimated());
+
+        HyperMinHashRepr::parse(value.bytes()).unwrap().set_estimate(42);
+        let blob = dump(&HyperMinHashRepr::parse(value.bytes()).unwrap());
+        let mut restored = restore(&blob).unwrap();
+        let repr = HyperMinHashRepr::parse(restored.bytes()).unwrap();
+        assert!(repr.estimated());
+        assert_eq!(repr.get_cache(), 42);
+        assert_same_registers(&mut value, &mut restored);
+    }
+
+    #[test]
+    fn test_restore_invalid() {
+        let mut value = new_value(&Params::new(10, 4, 4).unwrap(), HashAlgorithm::Murmur3, 100);
+        let blob = dump(&HyperMinHashRepr::parse(value.bytes()).unwrap());
+
+        assert_eq!(restore(&blob[0..10]).err(), Some(RestoreError::Malformed));
+        assert_eq!(restore(b"HYMH0123456789012345678901").err(), Some(RestoreError::Malformed));
+
+        let mut corrupted = blob.clone();
+        corrupted[30] ^= 1;
+        assert_eq!(restore(&corrupted).err(), Some(RestoreError::ChecksumMismatch));
+
+        assert_eq!(restore(&blob[..blob.len() - 1]).err(), Some(RestoreError::ChecksumMismatch));
+
+        let mut newer = blob.clone();
+        newer[4] = VERSION + 1;
+        assert_eq!(restore(&newer).err(), Some(RestoreError::UnsupportedVersion));
+
+        // re-compute checksum after modification
+        let modify = |f: &dyn Fn(&mut Vec<u8>)| {
+            let mut body = blob[..blob.len() - CHECKSUM_LEN].to_vec();
+            f(&mut body);
+            let checksum = crc32(&body);
+            body.extend_from_slice(&checksum.to_le_bytes());
+            restore(&body).err()
+        };
+        assert_eq!(modify(&|b| b[18] ^= 1), Some(RestoreError::HashSeedMismatch));
+        assert_eq!(modify(&|b| b[9] = 0xff), Some(RestoreError::UnsupportedHash));
+        assert_eq!(modify(&|b| b[6] = 20), Some(RestoreError::InvalidParams));
+        assert_eq!(modify(&|b| b[5] = 2), Some(RestoreError::Malformed));
+        assert_eq!(modify(&|b| b[26] ^= 1), Some(RestoreError::Malformed));
+        // register value with too long pattern
+        assert_eq!(modify(&|b| b[HEADER_LEN + 3] = 0xff), Some(RestoreError::InvalidRegisters));
+    }
+}
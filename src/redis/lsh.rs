@@ -0,0 +1,109 @@
+//! MinHash LSH index Redis module data type.
+//!
+//! The index holds copies of indexed sketches, so that queries never access keys other than given ones.
+//! MH.LSH.INSERT and queries take the index and sketch keys at once, which have to be in the same hash slot
+//! on Redis Cluster. (e.g. by hash tags)
+
+use super::*;
+use crate::hyperminhash::lsh::LshIndex;
+use libc::{c_int, c_void, size_t};
+use std::ptr::null_mut;
+use std::slice::from_raw_parts;
+
+pub const TYPE_NAME: &str = "HyMinHLSH";
+pub const ENCODING_VERSION: c_int = 0;
+
+pub static mut LSH_INDEX_TYPE: *mut RedisModuleType = null_mut();
+
+pub fn type_methods() -> RedisModuleTypeMethods {
+    RedisModuleTypeMethods {
+        version: REDISMODULE_TYPE_METHOD_VERSION,
+        rdb_load: Some(rdb_load),
+        rdb_save: Some(rdb_save),
+        aof_rewrite: Some(aof_rewrite),
+        mem_usage: Some(mem_usage),
+        digest: Some(digest),
+        free: Some(free),
+        aux_load: None,
+        aux_save: None,
+        aux_save_triggers: 0,
+        free_effort: None,
+        unlink: None,
+        copy: Some(copy),
+        defrag: None,
+    }
+}
+
+extern "C" fn rdb_load(rdb: *mut RedisModuleIO, encver: c_int) -> *mut c_void {
+    if encver != ENCODING_VERSION {
+        return null_mut();
+    }
+
+    unsafe {
+        let mut len: size_t = 0;
+        let ptr = RedisModule_LoadStringBuffer(rdb, &mut len);
+        if ptr.is_null() {
+            return null_mut();
+        }
+        let index = LshIndex::from_bytes(from_raw_parts(ptr, len));
+        RedisModule_Free(ptr as *mut c_void);
+
+        match index {
+            None => null_mut(),
+            Some(index) => Box::into_raw(Box::new(index)) as *mut c_void,
+        }
+    }
+}
+
+extern "C" fn rdb_save(rdb: *mut RedisModuleIO, value: *mut c_void) {
+    unsafe {
+        let bytes = (*(value as *const LshIndex)).to_bytes();
+        RedisModule_SaveStringBuffer(rdb, bytes.as_ptr(), bytes.len());
+    }
+}
+
+/// Index is rewritten as MH.LSH.RESTORE command with serialized index.
+extern "C" fn aof_rewrite(aof: *mut RedisModuleIO, key: *mut RedisModuleString, value: *mut c_void) {
+    unsafe {
+        let bytes = (*(value as *const LshIndex)).to_bytes();
+        RedisModule_EmitAOF(
+            aof,
+            "MH.LSH.RESTORE\0".as_ptr(),
+            "sbc\0".as_ptr(),
+            key,
+            bytes.as_ptr(),
+            bytes.len(),
+            "REPLACE\0".as_ptr());
+    }
+}
+
+extern "C" fn mem_usage(value: *const c_void) -> size_t {
+    unsafe {
+        (*(value as *const LshIndex)).mem_usage()
+    }
+}
+
+extern "C" fn digest(md: *mut RedisModuleDigest, value: *mut c_void) {
+    unsafe {
+        let bytes = (*(value as *const LshIndex)).to_bytes();
+        RedisModule_DigestAddStringBuffer(md, bytes.as_ptr(), bytes.len());
+        RedisModule_DigestEndSequence(md);
+    }
+}
+
+extern "C" fn free(value: *mut c_void) {
+    unsafe {
+        drop(Box::from_raw(value as *mut LshIndex));
+    }
+}
+
+extern "C" fn copy(
+    _fromkey: *mut RedisModuleString,
+    _tokey: *mut RedisModuleString,
+    value: *const c_void) -> *mut c_void {
+
+    unsafe {
+        let index = &*(value as *const LshIndex);
+        Box::into_raw(Box::new(index.clone())) as *mut c_void
+    }
+}
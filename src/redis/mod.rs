@@ -3,12 +3,12 @@
 extern crate libc;
 
 mod command;
-mod dense;
-mod dma;
-mod repr;
+mod datatype;
+mod dump;
+mod lsh;
 
 use command::*;
-use libc::{c_double, c_int, c_longlong, size_t};
+use libc::{c_double, c_int, c_long, c_longlong, c_void, size_t};
 
 const MODULE_NAME: &str = "redis-hyperminhash";
 const MODULE_VERSION: c_int = 1;
@@ -19,14 +19,48 @@ const REDISMODULE_ERR: c_int = 1;
 
 const REDISMODULE_KEYTYPE_EMPTY: c_int = 0;
 const REDISMODULE_KEYTYPE_STRING: c_int = 1;
+const REDISMODULE_KEYTYPE_MODULE: c_int = 6;
 
 const REDISMODULE_READ: c_int = 1;
 const REDISMODULE_WRITE: c_int = REDISMODULE_READ << 1;
 
+const REDISMODULE_TYPE_METHOD_VERSION: u64 = 3;
+
 // Opaque types for Redis Module structs
 pub enum RedisModuleCtx {}
 pub enum RedisModuleString {}
 pub enum RedisModuleKey {}
+pub enum RedisModuleType {}
+pub enum RedisModuleIO {}
+pub enum RedisModuleDigest {}
+
+/// Corresponds to RedisModuleTypeMethods version 3
+#[repr(C)]
+pub struct RedisModuleTypeMethods {
+    version: u64,
+    rdb_load: Option<extern "C" fn(rdb: *mut RedisModuleIO, encver: c_int) -> *mut c_void>,
+    rdb_save: Option<extern "C" fn(rdb: *mut RedisModuleIO, value: *mut c_void)>,
+    aof_rewrite: Option<extern "C" fn(
+        aof: *mut RedisModuleIO,
+        key: *mut RedisModuleString,
+        value: *mut c_void)>,
+    mem_usage: Option<extern "C" fn(value: *const c_void) -> size_t>,
+    digest: Option<extern "C" fn(digest: *mut RedisModuleDigest, value: *mut c_void)>,
+    free: Option<extern "C" fn(value: *mut c_void)>,
+    aux_load: Option<extern "C" fn(rdb: *mut RedisModuleIO, encver: c_int, when: c_int) -> c_int>,
+    aux_save: Option<extern "C" fn(rdb: *mut RedisModuleIO, when: c_int)>,
+    aux_save_triggers: c_int,
+    free_effort: Option<extern "C" fn(key: *mut RedisModuleString, value: *const c_void) -> size_t>,
+    unlink: Option<extern "C" fn(key: *mut RedisModuleString, value: *const c_void)>,
+    copy: Option<extern "C" fn(
+        fromkey: *mut RedisModuleString,
+        tokey: *mut RedisModuleString,
+        value: *const c_void) -> *mut c_void>,
+    defrag: Option<extern "C" fn(
+        ctx: *mut c_void,
+        key: *mut RedisModuleString,
+        value: *mut *mut c_void) -> c_int>,
+}
 
 type RedisModuleCmdFunc = extern "C" fn(
     ctx: *mut RedisModuleCtx,
@@ -61,19 +95,47 @@ extern "C" {
         ctx: *mut RedisModuleCtx,
         d: c_double) -> c_int;
 
+    static RedisModule_ReplyWithStringBuffer: extern "C" fn(
+        ctx: *mut RedisModuleCtx,
+        buf: *const u8,
+        len: size_t) -> c_int;
+
+    static RedisModule_ReplyWithString: extern "C" fn(
+        ctx: *mut RedisModuleCtx,
+        str: *mut RedisModuleString) -> c_int;
+
+    static RedisModule_CreateString: extern "C" fn(
+        ctx: *mut RedisModuleCtx,
+        ptr: *const u8,
+        len: size_t) -> *mut RedisModuleString;
+
+    static RedisModule_ReplyWithNull: extern "C" fn(ctx: *mut RedisModuleCtx) -> c_int;
+
     static RedisModule_StringDMA: extern "C" fn(
         key: *mut RedisModuleKey,
         len: *mut size_t,
         mode: c_int) -> *mut u8;
 
-    static RedisModule_StringTruncate: extern "C" fn(
+    static RedisModule_StringSet: extern "C" fn(
         key: *mut RedisModuleKey,
-        newlen: size_t) -> c_int;
+        str: *mut RedisModuleString) -> c_int;
 
     static RedisModule_StringPtrLen: extern "C" fn(
         str: *const RedisModuleString,
         len: *mut size_t) -> *const u8;
 
+    static RedisModule_StringToLongLong: extern "C" fn(
+        str: *const RedisModuleString,
+        ll: *mut c_longlong) -> c_int;
+
+    static RedisModule_StringToDouble: extern "C" fn(
+        str: *const RedisModuleString,
+        d: *mut c_double) -> c_int;
+
+    static RedisModule_ReplyWithArray: extern "C" fn(
+        ctx: *mut RedisModuleCtx,
+        len: c_long) -> c_int;
+
     static RedisModule_OpenKey: extern "C" fn(
         ctx: *mut RedisModuleCtx,
         keyname: *mut RedisModuleString,
@@ -95,6 +157,49 @@ extern "C" {
 
     static RedisModule_ReplicateVerbatim: extern "C" fn(ctx: *mut RedisModuleCtx) -> c_int;
 
+    static RedisModule_IsKeysPositionRequest: extern "C" fn(ctx: *mut RedisModuleCtx) -> c_int;
+
+    static RedisModule_KeyAtPos: extern "C" fn(ctx: *mut RedisModuleCtx, pos: c_int);
+
+    static RedisModule_CreateDataType: extern "C" fn(
+        ctx: *mut RedisModuleCtx,
+        name: *const u8,
+        encver: c_int,
+        typemethods: *mut RedisModuleTypeMethods) -> *mut RedisModuleType;
+
+    static RedisModule_ModuleTypeSetValue: extern "C" fn(
+        key: *mut RedisModuleKey,
+        mt: *mut RedisModuleType,
+        value: *mut c_void) -> c_int;
+
+    static RedisModule_ModuleTypeGetType: extern "C" fn(key: *mut RedisModuleKey) -> *mut RedisModuleType;
+
+    static RedisModule_ModuleTypeGetValue: extern "C" fn(key: *mut RedisModuleKey) -> *mut c_void;
+
+    static RedisModule_SaveStringBuffer: extern "C" fn(
+        io: *mut RedisModuleIO,
+        str: *const u8,
+        len: size_t);
+
+    static RedisModule_LoadStringBuffer: extern "C" fn(
+        io: *mut RedisModuleIO,
+        lenptr: *mut size_t) -> *mut u8;
+
+    static RedisModule_EmitAOF: unsafe extern "C" fn(
+        io: *mut RedisModuleIO,
+        cmdname: *const u8,
+        fmt: *const u8,
+        ...);
+
+    static RedisModule_DigestAddStringBuffer: extern "C" fn(
+        md: *mut RedisModuleDigest,
+        ele: *const u8,
+        len: size_t);
+
+    static RedisModule_DigestEndSequence: extern "C" fn(md: *mut RedisModuleDigest);
+
+    static RedisModule_Free: extern "C" fn(ptr: *mut c_void);
+
 //    static RedisModule_Log: extern "C" fn(
 //        ctx: *mut RedisModuleCtx,
 //        level: *const u8,
@@ -118,6 +223,35 @@ extern "C" fn RedisModule_OnLoad(
             return REDISMODULE_ERR;
         }
 
+        let mut type_methods = datatype::type_methods();
+        datatype::HYPERMINHASH_TYPE = RedisModule_CreateDataType(
+            ctx,
+            format!("{}\0", datatype::TYPE_NAME).as_ptr(),
+            datatype::ENCODING_VERSION,
+            &mut type_methods);
+        if datatype::HYPERMINHASH_TYPE.is_null() {
+            return REDISMODULE_ERR;
+        }
+
+        let mut type_methods = lsh::type_methods();
+        lsh::LSH_INDEX_TYPE = RedisModule_CreateDataType(
+            ctx,
+            format!("{}\0", lsh::TYPE_NAME).as_ptr(),
+            lsh::ENCODING_VERSION,
+            &mut type_methods);
+        if lsh::LSH_INDEX_TYPE.is_null() {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.reserve\0".as_ptr(),
+            MinHashReserve_RedisCommand,
+            "write fast\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
         if RedisModule_CreateCommand(
             ctx,
             "mh.add\0".as_ptr(),
@@ -127,11 +261,29 @@ extern "C" fn RedisModule_OnLoad(
             return REDISMODULE_ERR;
         }
 
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.addtext\0".as_ptr(),
+            MinHashAddText_RedisCommand,
+            "write deny-oom\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.addhash\0".as_ptr(),
+            MinHashAddHash_RedisCommand,
+            "write fast\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
         if RedisModule_CreateCommand(
             ctx,
             "mh.count\0".as_ptr(),
             MinHashCount_RedisCommand,
-            "write fast\0".as_ptr(),
+            "write fast getkeys-api\0".as_ptr(),
             1, -1, 1) != REDISMODULE_OK {
             return REDISMODULE_ERR;
         }
@@ -145,11 +297,29 @@ extern "C" fn RedisModule_OnLoad(
             return REDISMODULE_ERR;
         }
 
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.fromhll\0".as_ptr(),
+            MinHashFromHll_RedisCommand,
+            "write deny-oom\0".as_ptr(),
+            1, -1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.tohll\0".as_ptr(),
+            MinHashToHll_RedisCommand,
+            "write deny-oom\0".as_ptr(),
+            1, 2, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
         if RedisModule_CreateCommand(
             ctx,
             "mh.similarity\0".as_ptr(),
             MinHashSimilarity_RedisCommand,
-            "readonly fast\0".as_ptr(),
+            "readonly fast getkeys-api\0".as_ptr(),
             1, -1, 1) != REDISMODULE_OK {
             return REDISMODULE_ERR;
         }
@@ -158,11 +328,182 @@ extern "C" fn RedisModule_OnLoad(
             ctx,
             "mh.intersection\0".as_ptr(),
             MinHashIntersection_RedisCommand,
+            "readonly fast getkeys-api\0".as_ptr(),
+            1, -1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.intersectstore\0".as_ptr(),
+            MinHashIntersectStore_RedisCommand,
+            "write deny-oom\0".as_ptr(),
+            1, -1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.eval\0".as_ptr(),
+            MinHashEval_RedisCommand,
+            "readonly getkeys-api\0".as_ptr(),
+            2, -1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.matrix\0".as_ptr(),
+            MinHashMatrix_RedisCommand,
+            "readonly\0".as_ptr(),
+            3, -1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.topk\0".as_ptr(),
+            MinHashTopK_RedisCommand,
+            "readonly getkeys-api\0".as_ptr(),
+            1, -1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.cluster\0".as_ptr(),
+            MinHashCluster_RedisCommand,
+            "readonly\0".as_ptr(),
+            3, -1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.funnel\0".as_ptr(),
+            MinHashFunnel_RedisCommand,
+            "readonly\0".as_ptr(),
+            1, -1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.churn\0".as_ptr(),
+            MinHashChurn_RedisCommand,
             "readonly fast\0".as_ptr(),
+            1, 2, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.stats\0".as_ptr(),
+            MinHashStats_RedisCommand,
+            "readonly fast\0".as_ptr(),
+            1, -1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.signature\0".as_ptr(),
+            MinHashSignature_RedisCommand,
+            "readonly\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.dump\0".as_ptr(),
+            MinHashDump_RedisCommand,
+            "readonly\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.restore\0".as_ptr(),
+            MinHashRestore_RedisCommand,
+            "write deny-oom\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.import\0".as_ptr(),
+            MinHashImport_RedisCommand,
+            "write deny-oom\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.export\0".as_ptr(),
+            MinHashExport_RedisCommand,
+            "readonly\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.lsh.create\0".as_ptr(),
+            MinHashLshCreate_RedisCommand,
+            "write deny-oom\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.lsh.insert\0".as_ptr(),
+            MinHashLshInsert_RedisCommand,
+            "write deny-oom\0".as_ptr(),
             1, -1, 1) != REDISMODULE_OK {
             return REDISMODULE_ERR;
         }
 
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.lsh.query\0".as_ptr(),
+            MinHashLshQuery_RedisCommand,
+            "readonly\0".as_ptr(),
+            1, 2, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.lsh.remove\0".as_ptr(),
+            MinHashLshRemove_RedisCommand,
+            "write\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.contains.query\0".as_ptr(),
+            MinHashContainsQuery_RedisCommand,
+            "readonly\0".as_ptr(),
+            1, 2, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
+        if RedisModule_CreateCommand(
+            ctx,
+            "mh.lsh.restore\0".as_ptr(),
+            MinHashLshRestore_RedisCommand,
+            "write deny-oom\0".as_ptr(),
+            1, 1, 1) != REDISMODULE_OK {
+            return REDISMODULE_ERR;
+        }
+
         REDISMODULE_OK
     }
 }
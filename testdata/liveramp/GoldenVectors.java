@@ -0,0 +1,28 @@
+import com.liveramp.hyperminhash.BetaMinHash;
+import com.liveramp.hyperminhash.BetaMinHashSerde;
+
+import java.nio.charset.StandardCharsets;
+import java.nio.file.Files;
+import java.nio.file.Paths;
+
+/**
+ * Generates golden vectors for src/hyperminhash/liveramp.rs.
+ *
+ * Serializes BetaMinHash of UTF-8 elements "id0" to "id999" into beta_minhash_1000.bin.
+ *
+ * <pre>
+ * $ javac -cp hyperminhash.jar GoldenVectors.java
+ * $ java -cp hyperminhash.jar:. GoldenVectors
+ * </pre>
+ */
+public class GoldenVectors {
+  public static void main(String[] args) throws Exception {
+    BetaMinHash sketch = new BetaMinHash();
+    for (int i = 0; i < 1000; i++) {
+      sketch.offer(("id" + i).getBytes(StandardCharsets.UTF_8));
+    }
+
+    byte[] bytes = new BetaMinHashSerde().toBytes(sketch);
+    Files.write(Paths.get("beta_minhash_1000.bin"), bytes);
+  }
+}